@@ -1,7 +1,12 @@
 global start
+global gdt64
+global stack_bottom
+global stack_top
 extern long_mode_start
 
-section .text
+; The boot code runs before paging is enabled, so it lives in the low `.boot` sections
+; that are linked at their physical addresses (see linker.ld)
+section .boot.text progbits alloc exec nowrite align=16
 bits 32
 start: 
     mov esp, stack_top
@@ -13,6 +18,7 @@ start:
     call check_long_mode
 
     call set_up_page_tables
+    call set_up_la57
     call enable_paging
 
     ; load the 64-bit GDT
@@ -27,7 +33,8 @@ start:
 ; PAGING
 
 set_up_page_tables:
-    ; map first P4 entry to P3 table
+    ; map first P4 entry to P3 table, this identity map is only needed until we
+    ; jumped to the higher half and is removed again once `rust_main` runs
     mov eax, p3_table
     or eax, 0b11 ; present + writable
     mov [p4_table], eax
@@ -37,10 +44,29 @@ set_up_page_tables:
     or eax, 0b11 ; present + writable
     mov [p3_table], eax
 
-    ; map last P4 entry recursively to the first P4 entry
-    mov eax, p4_table
+    ; map the kernel to the higher half, 0xffffffff80000000 is P4 entry 511
+    ; and P3 entry 510. It shares the P2 table with the identity map.
+    mov eax, p3_high_table
     or eax, 0b11 ; present + writable
     mov [p4_table + 511 * 8], eax
+    mov eax, p2_table
+    or eax, 0b11 ; present + writable
+    mov [p3_high_table + 510 * 8], eax
+
+    ; map the first GiB of physical memory at the start of the higher half
+    ; (P4 entry 256), this is the beginning of the direct physical map. Without
+    ; the `direct-map` feature `remove_identity_map` removes it again.
+    mov eax, p3_direct_table
+    or eax, 0b11 ; present + writable
+    mov [p4_table + 256 * 8], eax
+    mov eax, p2_table
+    or eax, 0b11 ; present + writable
+    mov [p3_direct_table], eax
+
+    ; map P4 entry 510 recursively to the P4 table (511 is taken by the kernel)
+    mov eax, p4_table
+    or eax, 0b11 ; present + writable
+    mov [p4_table + 510 * 8], eax
 
     ; map each P2 entry to a huge 2MiB page
     mov ecx, 0         ; counter variable
@@ -56,16 +82,83 @@ set_up_page_tables:
     cmp ecx, 512       ; if counter == 512, the whole P2 table is mapped
     jne .map_p2_table  ; else map the next entry
 
+    ; map the first 2MiB with 4KiB pages instead, so that the page below the
+    ; boot stack can be left unmapped as a guard page
+    mov eax, p1_table
+    or eax, 0b11 ; present + writable
+    mov [p2_table], eax
+    mov ecx, 0
+
+.map_p1_table:
+    mov eax, ecx
+    shl eax, 12        ; start address of ecx-th page
+    or eax, 0b11       ; present + writable
+    mov [p1_table + ecx * 8], eax
+
+    inc ecx
+    cmp ecx, 512
+    jne .map_p1_table
+
+    ; a stack overflow now page faults instead of overwriting the page tables
+    mov eax, stack_guard
+    shr eax, 12
+    mov dword [p1_table + eax * 8], 0
+
+    ret
+
+; Switches to 5-level paging if the CPU supports it (CPUID.(EAX=07H,ECX=0):ECX.LA57[bit 16]).
+; The P5 table points at the P4 table for both the identity map (entry 0) and the higher half
+; (entry 511), and takes over the recursive entry. Rust reads CR4.LA57 to find out.
+; `remove_identity_map` clears P4 entry 0 as well then, or the first GiB would stay mapped
+; through P5 entry 511.
+set_up_la57:
+    ; make sure leaf 7 exists
+    mov eax, 0
+    cpuid
+    cmp eax, 7
+    jb .no_la57
+
+    mov eax, 7
+    mov ecx, 0
+    cpuid
+    test ecx, 1 << 16
+    jz .no_la57
+
+    mov eax, p4_table
+    or eax, 0b11 ; present + writable
+    mov [p5_table], eax
+    mov [p5_table + 511 * 8], eax
+
+    ; move the recursive mapping to the P5 table
+    mov eax, p5_table
+    or eax, 0b11 ; present + writable
+    mov [p5_table + 510 * 8], eax
+    mov dword [p4_table + 510 * 8], 0
+
+    mov byte [la57_enabled], 1
+.no_la57:
     ret
 
 enable_paging:
-    ; load P4 to cr3 register (cpu uses this to access the P4 table)
+    ; load the top level table to cr3 register (cpu uses this to access the P4/P5 table)
     mov eax, p4_table
+    cmp byte [la57_enabled], 0
+    je .load_cr3
+    mov eax, p5_table
+.load_cr3:
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
     mov eax, cr4
     or eax, 1 << 5
+    ; and SSE (OSFXSR, OSXMMEXCPT), the Rust code is compiled for it. Every CPU
+    ; with long mode has SSE2, `fpu::init` adds XSAVE later.
+    or eax, 1 << 9 | 1 << 10
+    ; and LA57 for 5-level paging, this can only be set while paging is disabled
+    cmp byte [la57_enabled], 0
+    je .set_cr4
+    or eax, 1 << 12
+.set_cr4:
     mov cr4, eax
 
     ; set the long mode bit in the EFER MSR (model specific register)
@@ -74,9 +167,11 @@ enable_paging:
     or eax, 1 << 8
     wrmsr
 
-    ; enable paging in the cr0 register
+    ; enable paging in the cr0 register, SSE also needs the FPU not emulated (EM)
+    ; and monitored (MP)
     mov eax, cr0
-    or eax, 1 << 31
+    and eax, ~(1 << 2)
+    or eax, 1 << 31 | 1 << 1
     mov cr0, eax
 
     ret
@@ -91,7 +186,7 @@ check_multiboot:
     jne .no_multiboot
     ret
 .no_multiboot:
-    mov al, "0"
+    mov esi, no_multiboot_message
     jmp error
 
 ; CPUID is a CPU instruction that can be used to get various information about the CPU. 
@@ -128,7 +223,7 @@ check_cpuid:
     je .no_cpuid
     ret
 .no_cpuid:
-    mov al, "1"
+    mov esi, no_cpuid_message
     jmp error
 
 check_long_mode:
@@ -145,19 +240,35 @@ check_long_mode:
     jz .no_long_mode       ; If it's not set, there is no long mode
     ret
 .no_long_mode:
-    mov al, "2"
+    mov esi, no_long_mode_message
     jmp error
 
-; Prints `ERR: ` and the given error code to screen and hangs.
-; parameter: error code (in ascii) in al
+; Prints the given message white on red to screen and hangs. Only checks that
+; have to pass before Rust runs belong here, Rust detects everything else.
+; parameter: zero terminated message in esi
 error:
-    mov dword [0xb8000], 0x4f524f45
-    mov dword [0xb8004], 0x4f3a4f52
-    mov dword [0xb8008], 0x4f204f20
-    mov byte  [0xb800a], al
+    mov edi, 0xb8000
+    mov ah, 0x4f
+.next_char:
+    lodsb
+    test al, al
+    jz .halt
+    mov [edi], ax
+    add edi, 2
+    jmp .next_char
+.halt:
     hlt
+    jmp .halt
+
+section .boot.rodata progbits alloc noexec nowrite align=16
+no_multiboot_message:
+    db "ERROR: not loaded by a Multiboot2 compliant bootloader", 0
+no_cpuid_message:
+    db "ERROR: CPU doesn't support CPUID", 0
+no_long_mode_message:
+    db "ERROR: CPU doesn't support 64-bit long mode", 0
 
-section .rodata
+align 8
 gdt64:
     dq 0 ; zero entry
 .code: equ $ - gdt64
@@ -166,14 +277,27 @@ gdt64:
     dw $ - gdt64 - 1
     dq gdt64
 
-section .bss
-align 4096
+section .boot.bss nobits alloc noexec write align=4096
+p5_table:
+    resb 4096
 p4_table:
     resb 4096
 p3_table:
     resb 4096
+p3_high_table:
+    resb 4096
 p2_table:
     resb 4096
+p3_direct_table:
+    resb 4096
+p1_table:
+    resb 4096
+; unmapped in set_up_page_tables
+stack_guard:
+    resb 4096
+; the stack is moved to its higher half alias in boot64.asm
 stack_bottom:
     resb 4096 * 4
 stack_top:
+la57_enabled:
+    resb 1
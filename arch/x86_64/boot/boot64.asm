@@ -1,6 +1,11 @@
 global long_mode_start
+extern gdt64
 
-section .text
+; Must match `memory::KERNEL_OFFSET`
+KERNEL_OFFSET equ 0xffffffff80000000
+
+; We are still running identity mapped at this point
+section .boot.text progbits alloc exec nowrite align=16
 bits 64
 long_mode_start:
     ; load 0 into all data segment registers
@@ -11,11 +16,31 @@ long_mode_start:
     mov fs, ax
     mov gs, ax
 
-    ; call the rust main
+    ; jump to the higher half
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+bits 64
+higher_half_start:
+    ; the boot stack and the GDT are linked low, switch to their higher half
+    ; aliases so they stay valid when the identity map is removed
+    mov rax, KERNEL_OFFSET
+    add rsp, rax
+    lgdt [gdt64_high_pointer]
+
+    ; call the rust main, a zero frame pointer ends backtraces
     extern rust_main
+    xor rbp, rbp
     call rust_main
 
     ; print `OKAY` to screen
     mov rax, 0x2f592f412f4b2f4f
-    mov qword [0xb8000], rax
+    mov rbx, KERNEL_OFFSET + 0xb8000
+    mov qword [rbx], rax
     hlt
+
+section .rodata
+gdt64_high_pointer:
+    dw 15 ; zero entry + code segment - 1
+    dq gdt64 + KERNEL_OFFSET
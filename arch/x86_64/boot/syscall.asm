@@ -0,0 +1,100 @@
+global syscall_entry
+extern syscall_dispatch
+
+; Offsets into the per-CPU area header, see `percpu::Header`
+PERCPU_KERNEL_STACK equ 8
+PERCPU_USER_STACK equ 16
+
+; XMM0-15 and MXCSR, saved around `syscall_dispatch`. A multiple of 16, so the stack stays
+; aligned for `movdqa`.
+XMM_SAVE_SIZE equ 16 * 16 + 16
+; MXCSR the kernel runs with, all exceptions masked and round to nearest. Must match
+; `fpu::DEFAULT_MXCSR`.
+KERNEL_MXCSR equ 0x1f80
+
+; The `syscall` instruction jumps here in ring 0 with the user RIP in rcx and RFLAGS in r11,
+; interrupts masked by SFMASK and still on the user stack. The registers are saved as a
+; `syscall::SyscallFrame` on the per-CPU kernel stack and passed to `syscall_dispatch`, which
+; stores the result in the saved rax. `swapgs` brings in the kernel GS base on entry and the user
+; one again on return, except for `exit`, which leaves through `exit_user`.
+;
+; The kernel is compiled for SSE and uses XMM registers as scratch, so the program's XMM0-15
+; are saved below the frame and its MXCSR is swapped for the kernel's. Compiled code only uses
+; legacy SSE encodings, which leave the upper halves of YMM and ZMM registers alone.
+section .text
+bits 64
+syscall_entry:
+    swapgs
+    mov [gs:PERCPU_USER_STACK], rsp
+    mov rsp, [gs:PERCPU_KERNEL_STACK]
+
+    push qword [gs:PERCPU_USER_STACK]
+    push r11
+    push rcx
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+
+    ; 10 pushes keep the stack 16 byte aligned for the XMM area and the call
+    mov rdi, rsp
+    sub rsp, XMM_SAVE_SIZE
+    movdqa [rsp + 0 * 16], xmm0
+    movdqa [rsp + 1 * 16], xmm1
+    movdqa [rsp + 2 * 16], xmm2
+    movdqa [rsp + 3 * 16], xmm3
+    movdqa [rsp + 4 * 16], xmm4
+    movdqa [rsp + 5 * 16], xmm5
+    movdqa [rsp + 6 * 16], xmm6
+    movdqa [rsp + 7 * 16], xmm7
+    movdqa [rsp + 8 * 16], xmm8
+    movdqa [rsp + 9 * 16], xmm9
+    movdqa [rsp + 10 * 16], xmm10
+    movdqa [rsp + 11 * 16], xmm11
+    movdqa [rsp + 12 * 16], xmm12
+    movdqa [rsp + 13 * 16], xmm13
+    movdqa [rsp + 14 * 16], xmm14
+    movdqa [rsp + 15 * 16], xmm15
+    stmxcsr [rsp + 16 * 16]
+    mov dword [rsp + 16 * 16 + 4], KERNEL_MXCSR
+    ldmxcsr [rsp + 16 * 16 + 4]
+    call syscall_dispatch
+
+    ldmxcsr [rsp + 16 * 16]
+    movdqa xmm0, [rsp + 0 * 16]
+    movdqa xmm1, [rsp + 1 * 16]
+    movdqa xmm2, [rsp + 2 * 16]
+    movdqa xmm3, [rsp + 3 * 16]
+    movdqa xmm4, [rsp + 4 * 16]
+    movdqa xmm5, [rsp + 5 * 16]
+    movdqa xmm6, [rsp + 6 * 16]
+    movdqa xmm7, [rsp + 7 * 16]
+    movdqa xmm8, [rsp + 8 * 16]
+    movdqa xmm9, [rsp + 9 * 16]
+    movdqa xmm10, [rsp + 10 * 16]
+    movdqa xmm11, [rsp + 11 * 16]
+    movdqa xmm12, [rsp + 12 * 16]
+    movdqa xmm13, [rsp + 13 * 16]
+    movdqa xmm14, [rsp + 14 * 16]
+    movdqa xmm15, [rsp + 15 * 16]
+    add rsp, XMM_SAVE_SIZE
+
+    ; `syscall_dispatch` ended programs whose return address isn't in the user half, sysret to
+    ; a non-canonical one would fault in ring 0
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop rcx
+    pop r11
+    ; interrupts are still disabled, nothing runs on the user stack in ring 0
+    pop rsp
+    swapgs
+    o64 sysret
+
@@ -0,0 +1,132 @@
+global trampoline_start
+global trampoline_end
+global trampoline_params
+
+; Must match `smp::TRAMPOLINE_ADDRESS`
+TRAMPOLINE_BASE equ 0x8000
+
+; Address of `label` once the trampoline is copied to TRAMPOLINE_BASE
+%define RELOC(label) (TRAMPOLINE_BASE + (label - trampoline_start))
+
+; The application processor startup code. `smp::init` copies it below 1MiB, identity maps it
+; and fills in `trampoline_params` before sending the startup IPI, which starts the AP in real
+; mode at TRAMPOLINE_BASE. It goes through protected mode into long mode on the page table of
+; the bootstrap processor and jumps to the Rust entry point on its own stack.
+;
+; The parameters are for one AP only. An AP that isn't the one in `apic_id`, or that comes
+; after the BSP claimed the parameters because it gave up waiting, parks in real mode.
+section .rodata.trampoline progbits alloc noexec nowrite align=16
+bits 16
+trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+
+    ; x2APIC ids are in leaf 0xb, leaf 1 only has the low 8 bits
+    xor eax, eax
+    cpuid
+    cmp eax, 0xb
+    jb .xapic_id
+    mov eax, 0xb
+    xor ecx, ecx
+    cpuid
+    jmp .check_id
+.xapic_id:
+    mov eax, 1
+    cpuid
+    shr ebx, 24
+    mov edx, ebx
+.check_id:
+    cmp edx, [RELOC(trampoline_params.apic_id)]
+    jne .park
+
+    mov eax, 1
+    xchg [RELOC(trampoline_params.claimed)], eax
+    test eax, eax
+    jnz .park
+
+    o32 lgdt [RELOC(trampoline_gdt.pointer)]
+    mov eax, cr0
+    or eax, 1 ; protected mode
+    mov cr0, eax
+    jmp dword trampoline_gdt.code32:RELOC(protected_mode)
+.park:
+    hlt
+    jmp .park
+
+bits 32
+protected_mode:
+    mov ax, trampoline_gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; PAE and SSE, and LA57 if the BSP runs with 5-level paging
+    mov eax, cr4
+    or eax, 1 << 5 | 1 << 9 | 1 << 10
+    test dword [RELOC(trampoline_params.la57)], 1
+    jz .set_cr4
+    or eax, 1 << 12
+.set_cr4:
+    mov cr4, eax
+
+    mov eax, [RELOC(trampoline_params.cr3)]
+    mov cr3, eax
+
+    ; long mode bit in the EFER MSR
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 1 << 8
+    wrmsr
+
+    mov eax, cr0
+    and eax, ~(1 << 2) ; no FPU emulation, for SSE
+    or eax, 1 << 31 | 1 << 1 ; paging, monitor coprocessor
+    mov cr0, eax
+    jmp trampoline_gdt.code64:RELOC(long_mode)
+
+bits 64
+long_mode:
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [RELOC(trampoline_params.stack)]
+    mov rdi, [RELOC(trampoline_params.cpu)]
+    mov rax, [RELOC(trampoline_params.entry)]
+    ; a zero frame pointer ends backtraces
+    xor rbp, rbp
+    ; the entry point never returns, `call` only keeps the stack aligned like the ABI expects
+    call rax
+.halt:
+    hlt
+    jmp .halt
+
+align 8
+trampoline_gdt:
+    dq 0 ; zero entry
+.code32: equ $ - trampoline_gdt
+    dq 0x00cf9a000000ffff ; 32-bit code segment, 4GiB flat
+.data: equ $ - trampoline_gdt
+    dq 0x00cf92000000ffff ; data segment, 4GiB flat
+.code64: equ $ - trampoline_gdt
+    dq (1<<43) | (1<<44) | (1<<47) | (1<<53) ; 64-bit code segment
+.pointer:
+    dw $ - trampoline_gdt - 1
+    dd RELOC(trampoline_gdt)
+
+; Must match `smp::TrampolineParams`
+align 8
+trampoline_params:
+.cr3: dq 0
+.la57: dq 0
+.stack: dq 0
+.entry: dq 0
+.cpu: dq 0
+.apic_id: dq 0
+.claimed: dq 0
+trampoline_end:
@@ -0,0 +1,93 @@
+global enter_user
+global exit_user
+global user_test_start
+global user_test_end
+
+; Offset into the per-CPU area header, see `percpu::Header`
+PERCPU_USER_RETURN equ 24
+
+; Must match the user selectors in `interrupts::gdt`
+USER_DATA_SELECTOR equ 0x18 | 3
+USER_CODE_SELECTOR equ 0x20 | 3
+
+; Interrupts are enabled in user mode
+USER_RFLAGS equ 1 << 9
+
+; Must match `syscall::SYS_PRINT` and `syscall::SYS_EXIT`
+SYS_PRINT equ 0
+SYS_EXIT equ 3
+
+section .text
+bits 64
+; fn enter_user(entry: rdi, stack: rsi). Saves the callee saved registers and the stack pointer
+; in the per-CPU area and drops to ring 3 through iretq. Only returns through `exit_user`.
+enter_user:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [gs:PERCPU_USER_RETURN], rsp
+
+    push USER_DATA_SELECTOR
+    push rsi
+    push USER_RFLAGS
+    push USER_CODE_SELECTOR
+    push rdi
+
+    ; don't leak kernel values to user mode
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8, r8
+    xor r9, r9
+    xor r10, r10
+    xor r11, r11
+    xor r12, r12
+    xor r13, r13
+    xor r14, r14
+    xor r15, r15
+    ; user mode runs with the user GS base, the per-CPU area waits in the kernel GS base MSR
+    swapgs
+    iretq
+
+; fn exit_user() -> !. Returns from `enter_user` on the stack it was called on, dropping
+; whatever the kernel stack of the user program holds. Called from a system call or an exception
+; handler, which already did the `swapgs` pairing the one in `enter_user`. The kernel GS base is
+; loaded and stays so.
+exit_user:
+    mov rsp, [gs:PERCPU_USER_RETURN]
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+; The first user program, copied to user memory by `user::test`. It prints a message and then
+; writes to kernel memory, which has to kill it. Position independent, it runs at any address.
+section .rodata
+user_test_start:
+    lea rdi, [rel .message]
+    mov rsi, .message_end - .message
+    mov eax, SYS_PRINT
+    syscall
+
+    ; not user accessible, so this faults
+    mov rax, 0xffffffff80000000
+    mov qword [rax], 0
+
+    ; not reached
+    mov edi, 0
+    mov eax, SYS_EXIT
+    syscall
+.message:
+    db "Hello from ring 3!", 10
+.message_end:
+user_test_end:
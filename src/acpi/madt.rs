@@ -0,0 +1,182 @@
+use core::ptr;
+
+use memory::PhysicalAddress;
+use super::SdtHeader;
+
+pub const MAX_PROCESSORS: usize = 32;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+
+// MADT flag: the system also has dual 8259 PICs
+const PCAT_COMPAT: u32 = 1 << 0;
+// Local APIC flag: the processor is usable
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+// Entry types
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysicalAddress,
+    // First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+// Connection of an ISA IRQ to the global system interrupts of the I/O APICs
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/**
+The parts of the Multiple APIC Description Table we care about. Disabled processors are left
+out. The table is laid out as follows:
+    Offset  Size    Field
+    0       36      SDT header with signature "APIC"
+    36      4       physical address of the local APIC
+    40      4       flags, bit 0 set if 8259 PICs are present too
+    44      -       variable length entries, each starting with a type and a length byte
+**/
+pub struct Madt {
+    pub local_apic_address: PhysicalAddress,
+    pub has_pic: bool,
+    processors: [Processor; MAX_PROCESSORS],
+    processor_count: usize,
+    io_apics: [IoApic; MAX_IO_APICS],
+    io_apic_count: usize,
+    overrides: [InterruptOverride; MAX_OVERRIDES],
+    override_count: usize,
+}
+
+impl Madt {
+    pub fn processors(&self) -> &[Processor] {
+        &self.processors[..self.processor_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn overrides(&self) -> &[InterruptOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    // Where ISA IRQ `line` ends up. Without an override it's the GSI with the same number,
+    // active high and edge triggered like on the ISA bus, unless another IRQ was moved there
+    // (usually IRQ 0 to GSI 2, which leaves the cascade IRQ 2 without a GSI).
+    pub fn isa_irq(&self, line: u8) -> Option<InterruptOverride> {
+        if let Some(irq) = self.overrides().iter().find(|o| o.source == line) {
+            return Some(*irq);
+        }
+        if self.overrides().iter().any(|o| o.gsi == line as u32) {
+            return None;
+        }
+        Some(InterruptOverride{
+            source: line,
+            gsi: line as u32,
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+
+    fn add_processor(&mut self, processor: Processor) {
+        // Firmware may list a processor both as local APIC and as x2APIC
+        if self.processors().iter().any(|p| p.apic_id == processor.apic_id) {
+            return;
+        }
+        if self.processor_count == MAX_PROCESSORS {
+            println!("ignoring processor with APIC id {}", processor.apic_id);
+            return;
+        }
+        self.processors[self.processor_count] = processor;
+        self.processor_count += 1;
+    }
+}
+
+pub fn parse(header: &SdtHeader) -> Madt {
+    let (body, size) = header.body();
+    let mut madt = Madt{
+        local_apic_address: read::<u32>(body, 0) as PhysicalAddress,
+        has_pic: read::<u32>(body, 4) & PCAT_COMPAT != 0,
+        processors: [Processor{ processor_id: 0, apic_id: 0 }; MAX_PROCESSORS],
+        processor_count: 0,
+        io_apics: [IoApic{ id: 0, address: 0, gsi_base: 0 }; MAX_IO_APICS],
+        io_apic_count: 0,
+        overrides: [InterruptOverride{
+            source: 0,
+            gsi: 0,
+            active_low: false,
+            level_triggered: false,
+        }; MAX_OVERRIDES],
+        override_count: 0,
+    };
+
+    let mut offset = 8;
+    while offset + 2 <= size {
+        let entry = body + offset;
+        let length = read::<u8>(entry, 1) as usize;
+        if length < 2 || offset + length > size {
+            println!("malformed MADT entry at offset {}", offset);
+            break;
+        }
+
+        match read::<u8>(entry, 0) {
+            LOCAL_APIC if read::<u32>(entry, 4) & PROCESSOR_ENABLED != 0 => {
+                madt.add_processor(Processor{
+                    processor_id: read::<u8>(entry, 2) as u32,
+                    apic_id: read::<u8>(entry, 3) as u32,
+                });
+            }
+            LOCAL_X2APIC if read::<u32>(entry, 8) & PROCESSOR_ENABLED != 0 => {
+                madt.add_processor(Processor{
+                    processor_id: read::<u32>(entry, 12),
+                    apic_id: read::<u32>(entry, 4),
+                });
+            }
+            IO_APIC if madt.io_apic_count < MAX_IO_APICS => {
+                madt.io_apics[madt.io_apic_count] = IoApic{
+                    id: read::<u8>(entry, 2),
+                    address: read::<u32>(entry, 4) as PhysicalAddress,
+                    gsi_base: read::<u32>(entry, 8),
+                };
+                madt.io_apic_count += 1;
+            }
+            INTERRUPT_OVERRIDE if madt.override_count < MAX_OVERRIDES => {
+                let flags = read::<u16>(entry, 8);
+                // 0b00 means "conforms to the bus", which is active high and edge for ISA
+                madt.overrides[madt.override_count] = InterruptOverride{
+                    source: read::<u8>(entry, 3),
+                    gsi: read::<u32>(entry, 4),
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                };
+                madt.override_count += 1;
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = read::<u64>(entry, 4) as PhysicalAddress;
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    madt
+}
+
+// MADT entries aren't aligned
+fn read<T: Copy>(base: usize, offset: usize) -> T {
+    unsafe { ptr::read_unaligned((base + offset) as *const T) }
+}
@@ -0,0 +1,184 @@
+use core::mem::size_of;
+use core::ptr;
+
+use spin::Once;
+
+use memory::{self, CacheMode, MemoryController, PhysicalAddress, VirtualAddress};
+
+pub use self::madt::{Madt, Processor, IoApic, InterruptOverride};
+
+mod madt;
+
+static MADT: Once<Madt> = Once::new();
+static HPET_ADDRESS: Once<PhysicalAddress> = Once::new();
+
+/**
+Root System Description Pointer. Revision 2 and later append the fields from `length` on and
+point to the XSDT, which holds 64-bit table addresses instead of the 32-bit ones of the RSDT.
+**/
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Size of the revision 0 part of `Rsdp`
+const RSDP_V1_SIZE: usize = 20;
+
+// Header every System Description Table starts with
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    fn address(&self) -> VirtualAddress {
+        self as *const _ as VirtualAddress
+    }
+
+    // The data after the header
+    fn body(&self) -> (VirtualAddress, usize) {
+        (self.address() + size_of::<SdtHeader>(), self.length as usize - size_of::<SdtHeader>())
+    }
+}
+
+// Locates the ACPI tables and copies out what we need from the MADT and the HPET table.
+// Returns false without ACPI, in which case interrupts stay with the legacy PIC.
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            println!("no ACPI RSDP found");
+            return false;
+        }
+    };
+
+    let (root_address, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as PhysicalAddress, 8)
+    } else {
+        (rsdp.rsdt_address as PhysicalAddress, 4)
+    };
+    let root = match map_table(root_address, memory_controller) {
+        Some(root) => root,
+        None => return false,
+    };
+
+    // The root table is an array of physical table addresses
+    let (entries, size) = root.body();
+    for i in 0..size / entry_size {
+        let entry = entries + i * entry_size;
+        let address = unsafe {
+            if entry_size == 8 {
+                ptr::read_unaligned(entry as *const u64) as PhysicalAddress
+            } else {
+                ptr::read_unaligned(entry as *const u32) as PhysicalAddress
+            }
+        };
+        if let Some(table) = map_table(address, memory_controller) {
+            match &table.signature {
+                b"APIC" => {
+                    MADT.call_once(|| madt::parse(table));
+                }
+                b"HPET" => {
+                    HPET_ADDRESS.call_once(|| hpet_address(table));
+                }
+                _ => {}
+            }
+            memory_controller.iounmap(table.address());
+        }
+    }
+    memory_controller.iounmap(root.address());
+    true
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    MADT.try()
+}
+
+// Physical base of the HPET registers
+pub fn hpet() -> Option<PhysicalAddress> {
+    HPET_ADDRESS.try().cloned()
+}
+
+// Searches the first KiB of the EBDA and the BIOS area below 1MiB on 16 byte boundaries
+fn find_rsdp() -> Option<&'static Rsdp> {
+    // The real mode segment of the EBDA is stored at 0x40e
+    let ebda = unsafe { *(memory::kernel_phys_to_virt(0x40e) as *const u16) } as usize;
+    let areas = [(ebda << 4, 1024), (0xe0000, 0x20000)];
+
+    for &(start, size) in areas.iter() {
+        if start == 0 {
+            continue;
+        }
+        for i in 0..size / 16 {
+            let address = memory::kernel_phys_to_virt(start + i * 16);
+            let rsdp = unsafe { &*(address as *const Rsdp) };
+            if &rsdp.signature == b"RSD PTR " && checksum(address, RSDP_V1_SIZE) {
+                return Some(rsdp);
+            }
+        }
+    }
+    None
+}
+
+// Maps the table at `address` until it is iounmap'ed. Returns None if the mapping or the
+// checksum fails.
+fn map_table(address: PhysicalAddress, memory_controller: &mut MemoryController)
+    -> Option<&'static SdtHeader>
+{
+    // Map the header first, the length of the table is only known afterwards
+    let header = match memory_controller.ioremap_cache(address, size_of::<SdtHeader>(),
+                                                        CacheMode::WriteBack) {
+        Some(header) => header,
+        None => return None,
+    };
+    let length = unsafe { (*(header as *const SdtHeader)).length } as usize;
+    memory_controller.iounmap(header);
+    if length < size_of::<SdtHeader>() {
+        return None;
+    }
+
+    let table = match memory_controller.ioremap_cache(address, length, CacheMode::WriteBack) {
+        Some(table) => table,
+        None => return None,
+    };
+    if !checksum(table, length) {
+        memory_controller.iounmap(table);
+        return None;
+    }
+    Some(unsafe { &*(table as *const SdtHeader) })
+}
+
+/**
+The HPET table holds the register base as a Generic Address Structure:
+    Offset  Size    Field
+    36      4       event timer block id
+    40      4       address space (0 for memory), register bit width and offset, access size
+    44      8       address
+    52      1       HPET number
+    53      2       minimum periodic tick
+**/
+fn hpet_address(table: &SdtHeader) -> PhysicalAddress {
+    unsafe { ptr::read_unaligned((table.address() + 44) as *const u64) as PhysicalAddress }
+}
+
+// All bytes of an ACPI structure add up to 0
+fn checksum(address: VirtualAddress, length: usize) -> bool {
+    let bytes = unsafe { ::core::slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
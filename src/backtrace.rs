@@ -0,0 +1,313 @@
+use core::{fmt, mem, slice, str};
+
+use multiboot2::BootInformation;
+use spin::Once;
+
+use interrupts::gdt;
+use memory::{self, VirtualAddress};
+use percpu;
+use syscall;
+
+// Frames printed at most, in case the chain is corrupted into a loop
+const MAX_FRAMES: usize = 32;
+
+// Multiboot2 tag with the kernel's ELF section headers
+const ELF_SECTIONS_TAG: u32 = 9;
+const END_TAG: u32 = 0;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+// ELF64 section header
+#[allow(dead_code)]
+#[repr(C)]
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entry_size: u64,
+}
+
+// ELF64 symbol table entry
+#[allow(dead_code)]
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+struct SymbolTable {
+    symbols: &'static [Symbol],
+    strings: &'static [u8],
+}
+
+static SYMBOLS: Once<SymbolTable> = Once::new();
+
+per_cpu! {
+    // Bottom and top of the stack an AP starts on, see `set_start_stack`
+    static START_STACK: (VirtualAddress, VirtualAddress) = (0, 0);
+}
+
+// Bounds of the boot stack, see boot.asm. It's linked low like the rest of the boot sections.
+extern "C" {
+    static stack_bottom: u8;
+    static stack_top: u8;
+}
+
+/**
+Finds the kernel's symbol table. GRUB loads the sections that aren't part of the kernel image,
+like `.symtab` and `.strtab`, to some physical address and stores it in their section header;
+`memory::init` keeps the frame allocator away from them.
+**/
+pub fn init(boot_info: &BootInformation) {
+    let sections = match elf_sections(boot_info) {
+        Some(sections) => sections,
+        None => return,
+    };
+    let symtab = match sections.iter().find(|section| section.typ == SHT_SYMTAB) {
+        Some(symtab) if symtab.entry_size as usize == mem::size_of::<Symbol>() => symtab,
+        _ => return,
+    };
+    let strtab = match sections.get(symtab.link as usize) {
+        Some(strtab) => strtab,
+        None => return,
+    };
+    match (section_data(symtab), section_data(strtab)) {
+        (Some(symbols), Some(strings)) => {
+            let count = symbols.len() / mem::size_of::<Symbol>();
+            SYMBOLS.call_once(|| SymbolTable{
+                symbols: unsafe { slice::from_raw_parts(symbols.as_ptr() as *const Symbol, count) },
+                strings: strings,
+            });
+        }
+        _ => {}
+    }
+}
+
+// Records the stack the calling AP started on, so that backtraces can walk it
+pub fn set_start_stack(bottom: VirtualAddress, top: VirtualAddress) {
+    START_STACK.set((bottom, top));
+}
+
+// Return addresses of the calling functions, innermost first
+pub struct Frames {
+    frame_pointer: VirtualAddress,
+    // Bounds of the stack the walk started on
+    stack_bottom: VirtualAddress,
+    stack_top: VirtualAddress,
+    count: usize,
+}
+
+/**
+Walks the stack from the caller up. The kernel is built with frame pointers, so every frame
+starts with the caller's RBP followed by the return address:
+
+    rbp + 8     return address
+    rbp         caller's rbp
+
+boot64.asm and the AP trampoline clear RBP before entering Rust, which ends the chain. The walk
+is used when panicking, so it must not fault: it stays on the stack it started on and stops at
+the first frame pointer outside of it, e.g. at an interrupt handler's frame, which points into
+the interrupted stack.
+**/
+#[inline(always)]
+pub fn frames() -> Frames {
+    let frame_pointer: VirtualAddress;
+    unsafe { asm!("movq %rbp, $0" : "=r"(frame_pointer) ::: "volatile") };
+    // On an unknown stack the walk ends right away
+    let (stack_bottom, stack_top) = current_stack(frame_pointer).unwrap_or((0, 0));
+    Frames{
+        frame_pointer: frame_pointer,
+        stack_bottom: stack_bottom,
+        stack_top: stack_top,
+        count: 0,
+    }
+}
+
+// The one of the calling CPU's stacks that `address` is on
+fn current_stack(address: VirtualAddress) -> Option<(VirtualAddress, VirtualAddress)> {
+    let boot_stack = unsafe {
+        (memory::kernel_phys_to_virt(&stack_bottom as *const u8 as usize),
+         memory::kernel_phys_to_virt(&stack_top as *const u8 as usize))
+    };
+    let mut stacks = [boot_stack; 6];
+    // Without the per-CPU area (early on or with the user GS base loaded) only the boot stack
+    // is known. Stacks that aren't set up yet have a top of 0.
+    if percpu::loaded() {
+        let cpu = percpu::cpu_id();
+        stacks[1] = START_STACK.get();
+        for (stack, &top) in stacks[2..5].iter_mut().zip(gdt::ist_stack_tops(cpu).iter()) {
+            *stack = below(top, gdt::IST_STACK_SIZE);
+        }
+        stacks[5] = below(percpu::kernel_stack(), syscall::KERNEL_STACK_SIZE);
+    }
+    stacks.iter().find(|&&(bottom, top)| bottom <= address && address < top).cloned()
+}
+
+// Bounds of a stack of `size` bytes ending at `top`
+fn below(top: VirtualAddress, size: usize) -> (VirtualAddress, VirtualAddress) {
+    if top == 0 { (0, 0) } else { (top - size, top) }
+}
+
+impl Iterator for Frames {
+    type Item = VirtualAddress;
+
+    fn next(&mut self) -> Option<VirtualAddress> {
+        let frame_pointer = self.frame_pointer;
+        // Both the caller's RBP and the return address have to be on the stack
+        if self.count == MAX_FRAMES || frame_pointer < self.stack_bottom
+            || self.stack_top.saturating_sub(frame_pointer) < 16 || frame_pointer % 8 != 0
+        {
+            return None;
+        }
+        let (caller_frame, return_address) = unsafe {
+            let frame = frame_pointer as *const VirtualAddress;
+            (*frame, *frame.offset(1))
+        };
+        // Callers' frames are further up the stack, anything else is garbage
+        self.frame_pointer = if caller_frame > frame_pointer { caller_frame } else { 0 };
+        self.count += 1;
+        if return_address == 0 { None } else { Some(return_address) }
+    }
+}
+
+// The function containing `address` and the offset into it
+pub fn symbolize(address: VirtualAddress) -> Option<(Demangle, usize)> {
+    let table = match SYMBOLS.try() {
+        Some(table) => table,
+        None => return None,
+    };
+    let address = address as u64;
+    let symbol = table.symbols.iter().find(|symbol| {
+        symbol.info & 0xf == STT_FUNC && symbol.value <= address
+            && address < symbol.value + symbol.size
+    });
+    symbol.and_then(|symbol| {
+        table.strings.get(symbol.name as usize..).and_then(|name| {
+            let length = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+            str::from_utf8(&name[..length]).ok()
+        }).map(|name| (Demangle(name), (address - symbol.value) as usize))
+    })
+}
+
+/**
+Displays a symbol name in the legacy Rust mangling readably, e.g.
+`_ZN8rustbelt10interrupts4init17h0123456789abcdefE` as `rustbelt::interrupts::init`.
+Other names are shown as they are.
+**/
+pub struct Demangle(&'static str);
+
+// Replacements of the escapes rustc uses for characters that aren't allowed in symbols
+const ESCAPES: [(&str, &str); 16] = [
+    ("$SP$", "@"), ("$BP$", "*"), ("$RF$", "&"), ("$LT$", "<"), ("$GT$", ">"), ("$LP$", "("),
+    ("$RP$", ")"), ("$C$", ","), ("$u7e$", "~"), ("$u20$", " "), ("$u27$", "'"),
+    ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"), ("$u3b$", ";"),
+];
+
+impl fmt::Display for Demangle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.0.starts_with("_ZN") || !self.0.ends_with('E') {
+            return f.write_str(self.0);
+        }
+        let mut rest = &self.0[3..self.0.len() - 1];
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(|&byte| is_digit(byte)).count();
+            let length = match rest[..digits].parse::<usize>() {
+                Ok(length) if digits + length <= rest.len() => length,
+                _ => return f.write_str(self.0),
+            };
+            let element = &rest[digits..digits + length];
+            rest = &rest[digits + length..];
+            // The last element is a hash telling apart different versions of the same crate
+            if rest.is_empty() && is_hash(element) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_element(f, element)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_hash(element: &str) -> bool {
+    element.len() == 17 && element.starts_with('h')
+        && element[1..].bytes().all(|byte| is_digit(byte) || (b'a' <= byte && byte <= b'f'))
+}
+
+fn is_digit(byte: u8) -> bool {
+    b'0' <= byte && byte <= b'9'
+}
+
+fn write_element(f: &mut fmt::Formatter, element: &str) -> fmt::Result {
+    // Elements starting with an escape get an underscore in front
+    let mut rest = if element.starts_with("_$") { &element[1..] } else { element };
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+        } else if rest.starts_with('$') {
+            match ESCAPES.iter().find(|&&(escape, _)| rest.starts_with(escape)) {
+                Some(&(escape, replacement)) => {
+                    f.write_str(replacement)?;
+                    rest = &rest[escape.len()..];
+                }
+                None => return f.write_str(rest),
+            }
+        } else {
+            let end = rest[1..].find(|c| c == '$' || c == '.').map_or(rest.len(), |end| end + 1);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+// The section headers in the multiboot information
+fn elf_sections(boot_info: &BootInformation) -> Option<&'static [SectionHeader]> {
+    let start = boot_info as *const _ as usize;
+    let end = start + boot_info.total_size as usize;
+    // Tags follow the 8 byte fixed part and are 8 byte aligned
+    let mut tag = start + 8;
+    while tag + 8 <= end {
+        let (typ, size) = unsafe { (*(tag as *const u32), *((tag + 4) as *const u32)) };
+        match typ {
+            END_TAG => break,
+            ELF_SECTIONS_TAG => {
+                // type, size, number of sections, entry size, string table index, headers
+                let count = unsafe { *((tag + 8) as *const u32) } as usize;
+                let headers = (tag + 20) as *const SectionHeader;
+                return Some(unsafe { slice::from_raw_parts(headers, count) });
+            }
+            _ => tag += (size as usize + 7) & !7,
+        }
+    }
+    None
+}
+
+// Contents of a section, if it's loaded and reachable
+fn section_data(section: &SectionHeader) -> Option<&'static [u8]> {
+    let address = section.addr as usize;
+    let size = section.size as usize;
+    let virtual_address = if address >= memory::KERNEL_OFFSET {
+        address
+    } else if address != 0 && address + size <= 1 << 30 {
+        memory::kernel_phys_to_virt(address)
+    } else {
+        return None;
+    };
+    Some(unsafe { slice::from_raw_parts(virtual_address as *const u8, size) })
+}
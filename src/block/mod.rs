@@ -0,0 +1,34 @@
+pub use self::ramdisk::RamDisk;
+
+mod ramdisk;
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // The request runs past the last block of the device
+    OutOfRange,
+    // The buffer isn't a multiple of `BLOCK_SIZE`
+    UnalignedBuffer,
+    // The device reported a failure
+    Io,
+}
+
+// A device addressed in `BLOCK_SIZE` byte blocks. Buffers cover `buffer.len() / BLOCK_SIZE`
+// consecutive blocks starting at `block`.
+pub trait BlockDevice {
+    fn block_count(&self) -> usize;
+    fn read_blocks(&mut self, block: usize, buffer: &mut [u8]) -> Result<(), BlockError>;
+    fn write_blocks(&mut self, block: usize, buffer: &[u8]) -> Result<(), BlockError>;
+}
+
+// Common argument checks for `BlockDevice` implementations
+fn check_request(device: &BlockDevice, block: usize, len: usize) -> Result<(), BlockError> {
+    if len % BLOCK_SIZE != 0 {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    if block + len / BLOCK_SIZE > device.block_count() {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use core::ptr;
+
+use block::{BlockDevice, BlockError, BLOCK_SIZE, check_request};
+use memory::VirtualAddress;
+
+// A block device backed by kernel memory, e.g. a `vmalloc` area. Used as a swap device for
+// testing.
+pub struct RamDisk {
+    base: VirtualAddress,
+    blocks: usize,
+}
+
+impl RamDisk {
+    // `base` must point at `size` bytes of mapped memory that stays valid as long as the disk
+    pub unsafe fn new(base: VirtualAddress, size: usize) -> RamDisk {
+        RamDisk{
+            base: base,
+            blocks: size / BLOCK_SIZE,
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> usize {
+        self.blocks
+    }
+
+    fn read_blocks(&mut self, block: usize, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, block, buffer.len())?;
+        let source = (self.base + block * BLOCK_SIZE) as *const u8;
+        unsafe { ptr::copy_nonoverlapping(source, buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, block: usize, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, block, buffer.len())?;
+        let destination = (self.base + block * BLOCK_SIZE) as *mut u8;
+        unsafe { ptr::copy_nonoverlapping(buffer.as_ptr(), destination, buffer.len()) };
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use core::fmt;
+
+use super::cpuid;
+
+pub const MAX_CACHES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cache {
+    pub level: u32,
+    pub typ: CacheType,
+    // In bytes
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let typ = match self.typ {
+            CacheType::Data => "d",
+            CacheType::Instruction => "i",
+            CacheType::Unified => "",
+        };
+        write!(f, "L{}{}: {} KiB, {}-way, {} byte lines",
+               self.level, typ, self.size / 1024, self.ways, self.line_size)
+    }
+}
+
+/**
+Enumerates the caches with the deterministic cache parameters leaf, 04H on Intel and
+8000001DH on AMD. Each subleaf describes one cache until the type is 0:
+    Register  Bit(s)  Name
+    EAX       0-4     type, 1: data, 2: instruction, 3: unified
+    EAX       5-7     level
+    EBX       0-11    line size - 1
+    EBX       12-21   physical line partitions - 1
+    EBX       22-31   ways - 1
+    ECX       0-31    sets - 1
+**/
+pub fn detect(vendor: &[u8; 12], max_leaf: u32, max_extended_leaf: u32)
+    -> [Option<Cache>; MAX_CACHES]
+{
+    let mut caches = [None; MAX_CACHES];
+    let leaf = if vendor == b"GenuineIntel" && max_leaf >= 4 {
+        4
+    } else if vendor == b"AuthenticAMD" && max_extended_leaf >= 0x8000_001d {
+        0x8000_001d
+    } else {
+        return caches;
+    };
+
+    for (subleaf, cache) in caches.iter_mut().enumerate() {
+        let result = cpuid(leaf, subleaf as u32);
+        let typ = match result.eax & 0x1f {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => break,
+        };
+        let line_size = (result.ebx & 0xfff) as usize + 1;
+        let partitions = ((result.ebx >> 12) & 0x3ff) as usize + 1;
+        let ways = (result.ebx >> 22) as usize + 1;
+        let sets = result.ecx as usize + 1;
+        *cache = Some(Cache{
+            level: (result.eax >> 5) & 0x7,
+            typ: typ,
+            size: ways * partitions * line_size * sets,
+            line_size: line_size,
+            ways: ways,
+        });
+    }
+    caches
+}
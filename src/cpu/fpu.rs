@@ -0,0 +1,173 @@
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT};
+
+use super::{cpuid, has, read_cr0, read_cr4, write_cr0, write_cr4, Features};
+
+const CR0_MONITOR_COPROCESSOR: u64 = 1 << 1;
+const CR0_EMULATION: u64 = 1 << 2;
+const CR0_NUMERIC_ERROR: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+// XCR0 state components
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+// Opmask, upper halves of ZMM0-15 and ZMM16-31
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+// Large enough for x87, SSE, AVX and AVX-512 state
+pub const MAX_STATE_SIZE: usize = 4096;
+// Default control words: all exceptions masked, round to nearest
+const DEFAULT_FCW: u16 = 0x037f;
+// Also what the kernel runs with, see KERNEL_MXCSR in syscall.asm
+const DEFAULT_MXCSR: u32 = 0x1f80;
+// Offsets in the legacy (FXSAVE) area and of the XSAVE header
+const MXCSR_OFFSET: usize = 24;
+const XSTATE_BV_OFFSET: usize = 512;
+
+static XSAVE_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+static XCR0: AtomicUsize = ATOMIC_USIZE_INIT;
+// Bytes `save` writes with the enabled XCR0 components
+static STATE_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+per_cpu! {
+    // Where the interrupted context's state goes while the kernel uses SIMD in `with_simd`
+    static KERNEL_SAVE_AREA: ExtendedState = ExtendedState::new();
+}
+
+/**
+The x87, SSE and (with XSAVE) AVX register state of one thread.
+
+The kernel is compiled for SSE (see x86_64-rustbelt.json), so compiled code uses XMM0-15 as
+scratch registers, always with legacy encodings that leave the upper halves of YMM/ZMM alone.
+It never uses x87 or MMX and never changes MXCSR. Only those sixteen registers have to be
+protected from it: interrupt handlers preserve every register they touch and `syscall_entry`
+saves them around the dispatcher, loading the kernel's MXCSR as well. Everything else belongs
+to the user program until it ends, `user::run` loads a fresh state before entering ring 3.
+
+Interrupt handlers run with the MXCSR of the code they interrupted, so they must not do
+floating point arithmetic, which could raise unmasked SIMD exceptions.
+**/
+#[repr(C, align(64))]
+pub struct ExtendedState {
+    area: [u8; MAX_STATE_SIZE],
+}
+
+impl ExtendedState {
+    // The initial state, with the default x87 and SSE control words
+    pub const fn new() -> ExtendedState {
+        ExtendedState{
+            area: [0; MAX_STATE_SIZE],
+        }
+    }
+
+    // `new` can't set the control words in a const fn, this has to run before the first
+    // `restore`
+    pub fn reset(&mut self) {
+        for byte in self.area.iter_mut() {
+            *byte = 0;
+        }
+        unsafe {
+            ptr::write(self.area.as_mut_ptr() as *mut u16, DEFAULT_FCW);
+            ptr::write(self.area[MXCSR_OFFSET..].as_mut_ptr() as *mut u32, DEFAULT_MXCSR);
+            // Load the x87 and SSE parts from the area, the other components start in
+            // their initial state
+            ptr::write(self.area[XSTATE_BV_OFFSET..].as_mut_ptr() as *mut u64,
+                       XCR0_X87 | XCR0_SSE);
+        }
+    }
+
+    pub fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if XSAVE_ENABLED.load(Ordering::Relaxed) {
+                asm!("xsave64 ($0)"
+                     :: "r"(area), "{eax}"(u32::max_value()), "{edx}"(u32::max_value())
+                     : "memory" : "volatile");
+            } else {
+                asm!("fxsave64 ($0)" :: "r"(area) : "memory" : "volatile");
+            }
+        }
+    }
+
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if XSAVE_ENABLED.load(Ordering::Relaxed) {
+                asm!("xrstor64 ($0)"
+                     :: "r"(area), "{eax}"(u32::max_value()), "{edx}"(u32::max_value())
+                     : "memory" : "volatile");
+            } else {
+                asm!("fxrstor64 ($0)" :: "r"(area) : "memory" : "volatile");
+            }
+        }
+    }
+}
+
+// Enables SSE and, if available, XSAVE with every component the CPU and this module support.
+// Has to run on each CPU.
+pub fn init() {
+    let cr0 = read_cr0() & !CR0_EMULATION;
+    let mut cr4 = read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+    unsafe { write_cr0(cr0 | CR0_MONITOR_COPROCESSOR | CR0_NUMERIC_ERROR) };
+
+    if !has(Features::XSAVE) {
+        unsafe { write_cr4(cr4) };
+        STATE_SIZE.store(512, Ordering::Relaxed);
+        return;
+    }
+    cr4 |= CR4_OSXSAVE;
+    unsafe { write_cr4(cr4) };
+
+    // CPUID.0DH:EAX lists the components XCR0 may contain
+    let supported = cpuid(0xd, 0).eax as u64;
+    let mut xcr0 = XCR0_X87 | XCR0_SSE;
+    if has(Features::AVX) {
+        xcr0 |= XCR0_AVX;
+        if has(Features::AVX512F) && supported & XCR0_AVX512 == XCR0_AVX512 {
+            xcr0 |= XCR0_AVX512;
+        }
+    }
+    unsafe { xsetbv(0, xcr0 & supported) };
+
+    // CPUID.0DH:EBX is the area size for the components now enabled in XCR0
+    let size = cpuid(0xd, 0).ebx as usize;
+    assert!(size <= MAX_STATE_SIZE, "XSAVE area of {} bytes is too large", size);
+    STATE_SIZE.store(size, Ordering::Relaxed);
+    XCR0.store((xcr0 & supported) as usize, Ordering::Relaxed);
+    XSAVE_ENABLED.store(true, Ordering::Relaxed);
+}
+
+// The enabled XCR0 components, 0 without XSAVE
+pub fn xcr0() -> u64 {
+    XCR0.load(Ordering::Relaxed) as u64
+}
+
+pub fn state_size() -> usize {
+    STATE_SIZE.load(Ordering::Relaxed)
+}
+
+/**
+Runs `f`, which may use any SIMD extension the CPU has, e.g. by calling functions compiled with
+`#[target_feature]` for AVX or through inline assembly. The interrupted thread's extended state
+is saved to a per-CPU area before and restored afterwards. Interrupts are disabled in between,
+so `f` should be short, and calls must not be nested. Needs the per-CPU area.
+**/
+pub fn with_simd<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    KERNEL_SAVE_AREA.with(|save_area| {
+        save_area.save();
+        let result = f();
+        save_area.restore();
+        result
+    })
+}
+
+unsafe fn xsetbv(register: u32, value: u64) {
+    asm!("xsetbv" :: "{ecx}"(register), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+         :: "volatile");
+}
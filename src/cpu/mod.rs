@@ -0,0 +1,253 @@
+use core::{fmt, str};
+
+use spin::Once;
+
+pub use self::cache::{Cache, CacheType};
+
+mod cache;
+pub mod fpu;
+
+static CPU_INFO: Once<CpuInfo> = Once::new();
+
+// Registers returned by a single `cpuid` query
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :
+             : "volatile");
+    }
+    CpuidResult{
+        eax: eax,
+        ebx: ebx,
+        ecx: ecx,
+        edx: edx,
+    }
+}
+
+bitflags! {
+    // CPU features subsystems pick their code paths with. Long mode, CPUID and everything
+    // else boot.asm relies on are implied.
+    pub struct Features: u64 {
+        const APIC = 1 << 0;
+        const X2APIC = 1 << 1;
+        const TSC_DEADLINE = 1 << 2;
+        const INVARIANT_TSC = 1 << 3;
+        const PAT = 1 << 4;
+        const PGE = 1 << 5;
+        const PCID = 1 << 6;
+        const INVPCID = 1 << 7;
+        const NX = 1 << 8;
+        const PAGE_1GIB = 1 << 9;
+        const LA57 = 1 << 10;
+        const SMEP = 1 << 11;
+        const SMAP = 1 << 12;
+        const FSGSBASE = 1 << 13;
+        const SYSCALL = 1 << 14;
+        const FXSR = 1 << 15;
+        const SSE = 1 << 16;
+        const SSE2 = 1 << 17;
+        const SSE3 = 1 << 18;
+        const SSSE3 = 1 << 19;
+        const SSE4_1 = 1 << 20;
+        const SSE4_2 = 1 << 21;
+        const XSAVE = 1 << 22;
+        const AVX = 1 << 23;
+        const AVX2 = 1 << 24;
+        const AVX512F = 1 << 25;
+        const RDRAND = 1 << 26;
+        const RDSEED = 1 << 27;
+        const HYPERVISOR = 1 << 28;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+// (leaf, register, bit, feature) for every feature in `Features`
+const FEATURE_BITS: [(u32, Register, u32, Features); 29] = [
+    (0x1, Register::Ecx, 0, Features::SSE3),
+    (0x1, Register::Ecx, 9, Features::SSSE3),
+    (0x1, Register::Ecx, 17, Features::PCID),
+    (0x1, Register::Ecx, 19, Features::SSE4_1),
+    (0x1, Register::Ecx, 20, Features::SSE4_2),
+    (0x1, Register::Ecx, 21, Features::X2APIC),
+    (0x1, Register::Ecx, 24, Features::TSC_DEADLINE),
+    (0x1, Register::Ecx, 26, Features::XSAVE),
+    (0x1, Register::Ecx, 28, Features::AVX),
+    (0x1, Register::Ecx, 30, Features::RDRAND),
+    (0x1, Register::Ecx, 31, Features::HYPERVISOR),
+    (0x1, Register::Edx, 9, Features::APIC),
+    (0x1, Register::Edx, 13, Features::PGE),
+    (0x1, Register::Edx, 16, Features::PAT),
+    (0x1, Register::Edx, 24, Features::FXSR),
+    (0x1, Register::Edx, 25, Features::SSE),
+    (0x1, Register::Edx, 26, Features::SSE2),
+    (0x7, Register::Ebx, 0, Features::FSGSBASE),
+    (0x7, Register::Ebx, 5, Features::AVX2),
+    (0x7, Register::Ebx, 7, Features::SMEP),
+    (0x7, Register::Ebx, 10, Features::INVPCID),
+    (0x7, Register::Ebx, 16, Features::AVX512F),
+    (0x7, Register::Ebx, 18, Features::RDSEED),
+    (0x7, Register::Ebx, 20, Features::SMAP),
+    (0x7, Register::Ecx, 16, Features::LA57),
+    (0x8000_0001, Register::Edx, 11, Features::SYSCALL),
+    (0x8000_0001, Register::Edx, 20, Features::NX),
+    (0x8000_0001, Register::Edx, 26, Features::PAGE_1GIB),
+    (0x8000_0007, Register::Edx, 8, Features::INVARIANT_TSC),
+];
+
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Features,
+    caches: [Option<Cache>; cache::MAX_CACHES],
+}
+
+impl CpuInfo {
+    // E.g. "GenuineIntel" or "AuthenticAMD"
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    pub fn caches(&self) -> &[Option<Cache>] {
+        &self.caches
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "CPU: {} family {:#x} model {:#x} stepping {}",
+                 self.vendor(), self.family, self.model, self.stepping)?;
+        writeln!(f, "    features: {:?}", self.features)?;
+        for cache in self.caches.iter().filter_map(|cache| *cache) {
+            writeln!(f, "    {}", cache)?;
+        }
+        Ok(())
+    }
+}
+
+// Decodes the CPUID leaves once, later callers use `info` or `has`. Also enables SSE/AVX.
+pub fn init() {
+    let info = CPU_INFO.call_once(detect);
+    print!("{}", info);
+    fpu::init();
+    println!("    extended state: {} bytes, XCR0 {:#x}", fpu::state_size(), fpu::xcr0());
+}
+
+// Application processors are assumed to have the same features as the BSP
+pub fn init_ap() {
+    fpu::init();
+}
+
+pub fn info() -> &'static CpuInfo {
+    CPU_INFO.try().expect("cpu::init not called")
+}
+
+pub fn has(features: Features) -> bool {
+    info().features.contains(features)
+}
+
+// APIC id the calling CPU was assigned at reset, the full x2APIC id if the CPU has leaf 0BH
+pub fn initial_apic_id() -> u32 {
+    if cpuid(0, 0).eax >= 0xb {
+        cpuid(0xb, 0).edx
+    } else {
+        cpuid(1, 0).ebx >> 24
+    }
+}
+
+pub fn read_cr0() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr0, $0" : "=r"(value) ::: "volatile") };
+    value
+}
+
+pub unsafe fn write_cr0(value: u64) {
+    asm!("mov $0, %cr0" :: "r"(value) : "memory" : "volatile");
+}
+
+pub fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr4, $0" : "=r"(value) ::: "volatile") };
+    value
+}
+
+pub unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile");
+}
+
+fn detect() -> CpuInfo {
+    let leaf_0 = cpuid(0, 0);
+    let max_leaf = leaf_0.eax;
+    let max_extended_leaf = cpuid(0x8000_0000, 0).eax;
+
+    // The vendor string is stored in EBX, EDX, ECX
+    let mut vendor = [0; 12];
+    for (i, register) in [leaf_0.ebx, leaf_0.edx, leaf_0.ecx].iter().enumerate() {
+        for byte in 0..4 {
+            vendor[i * 4 + byte] = (register >> (byte * 8)) as u8;
+        }
+    }
+
+    /*
+    CPUID.01H:EAX holds the processor signature:
+        Bit(s)  Name
+        0-3     stepping
+        4-7     model
+        8-11    family
+        16-19   extended model, prepended to model for family 6 and 15
+        20-27   extended family, added to family 15
+    */
+    let signature = cpuid(1, 0).eax;
+    let mut family = (signature >> 8) & 0xf;
+    let mut model = (signature >> 4) & 0xf;
+    if family == 0xf {
+        family += (signature >> 20) & 0xff;
+    }
+    if family == 0x6 || family >= 0xf {
+        model |= ((signature >> 16) & 0xf) << 4;
+    }
+
+    let mut features = Features::empty();
+    for &(leaf, register, bit, feature) in FEATURE_BITS.iter() {
+        let supported = if leaf >= 0x8000_0000 { max_extended_leaf } else { max_leaf };
+        if leaf > supported {
+            continue;
+        }
+        let result = cpuid(leaf, 0);
+        let value = match register {
+            Register::Ebx => result.ebx,
+            Register::Ecx => result.ecx,
+            Register::Edx => result.edx,
+        };
+        if value & (1 << bit) != 0 {
+            features |= feature;
+        }
+    }
+
+    CpuInfo{
+        vendor: vendor,
+        family: family,
+        model: model,
+        stepping: signature & 0xf,
+        features: features,
+        caches: cache::detect(&vendor, max_leaf, max_extended_leaf),
+    }
+}
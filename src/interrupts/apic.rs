@@ -0,0 +1,162 @@
+use core::ptr;
+
+use spin::Once;
+use x86_64::registers::msr::{rdmsr, wrmsr};
+
+use cpu::{self, Features};
+use memory::{MemoryController, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+// In x2APIC mode register `offset` is MSR `X2APIC_MSR_BASE + offset / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets in the xAPIC MMIO page
+pub const ID: usize = 0x20;
+pub const VERSION: usize = 0x30;
+pub const TASK_PRIORITY: usize = 0x80;
+pub const EOI: usize = 0xb0;
+pub const SPURIOUS_VECTOR: usize = 0xf0;
+pub const ERROR_STATUS: usize = 0x280;
+pub const ICR_LOW: usize = 0x300;
+pub const ICR_HIGH: usize = 0x310;
+pub const LVT_TIMER: usize = 0x320;
+pub const LVT_LINT0: usize = 0x350;
+pub const LVT_LINT1: usize = 0x360;
+pub const LVT_ERROR: usize = 0x370;
+pub const TIMER_INITIAL_COUNT: usize = 0x380;
+pub const TIMER_CURRENT_COUNT: usize = 0x390;
+pub const TIMER_DIVIDE: usize = 0x3e0;
+
+// Vector of the local APIC timer, one of the `LOCAL_VECTOR_BASE` vectors
+pub const TIMER_VECTOR: u8 = super::LOCAL_VECTOR_BASE;
+
+// Vector for interrupts that vanished before the CPU acknowledged them. Those don't get an EOI.
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    XApic(VirtualAddress),
+    X2Apic,
+}
+
+// Every CPU sees its own local APIC through the same MMIO page or MSRs, so one `LocalApic`
+// serves all of them.
+pub struct LocalApic {
+    mode: Mode,
+}
+
+impl LocalApic {
+    pub fn read(&self, register: usize) -> u32 {
+        match self.mode {
+            Mode::XApic(base) => unsafe { ptr::read_volatile((base + register) as *const u32) },
+            Mode::X2Apic => unsafe { rdmsr(X2APIC_MSR_BASE + (register >> 4) as u32) as u32 },
+        }
+    }
+
+    pub fn write(&self, register: usize, value: u32) {
+        match self.mode {
+            Mode::XApic(base) => unsafe {
+                ptr::write_volatile((base + register) as *mut u32, value)
+            },
+            Mode::X2Apic => unsafe {
+                wrmsr(X2APIC_MSR_BASE + (register >> 4) as u32, value as u64)
+            },
+        }
+    }
+
+    // APIC id of the calling CPU
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            Mode::XApic(_) => self.read(ID) >> 24,
+            Mode::X2Apic => self.read(ID),
+        }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI, 0);
+    }
+
+    // Sends an interprocessor interrupt. `command` holds the ICR bits below the destination.
+    pub fn send_ipi(&self, apic_id: u32, command: u32) {
+        match self.mode {
+            Mode::XApic(_) => {
+                self.write(ICR_HIGH, apic_id << 24);
+                self.write(ICR_LOW, command);
+                while self.read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+            }
+            // The x2APIC ICR is a single 64-bit MSR and has no delivery status
+            Mode::X2Apic => unsafe {
+                wrmsr(X2APIC_MSR_BASE + (ICR_LOW >> 4) as u32,
+                      (apic_id as u64) << 32 | command as u64)
+            },
+        }
+    }
+
+    // Software enables the local APIC of the calling CPU. External interrupts arrive through
+    // the I/O APIC, so LINT0 is masked; LINT1 is wired to NMI on PC hardware.
+    fn enable(&self) {
+        unsafe {
+            let mut base = rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+            if let Mode::X2Apic = self.mode {
+                base |= APIC_BASE_X2APIC_ENABLE;
+            }
+            wrmsr(IA32_APIC_BASE, base);
+        }
+        self.write(TASK_PRIORITY, 0);
+        self.write(LVT_LINT0, LVT_MASKED);
+        self.write(LVT_LINT1, LVT_NMI);
+        self.write(LVT_ERROR, LVT_MASKED);
+        // The error status register has to be written before it can be read
+        self.write(ERROR_STATUS, 0);
+        self.write(SPURIOUS_VECTOR, SPURIOUS_APIC_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32);
+    }
+}
+
+// Enables the local APIC of the bootstrap processor, in x2APIC mode if the CPU supports it.
+// `address` is the MMIO base from the MADT. Returns false without an APIC.
+pub fn init(address: PhysicalAddress, memory_controller: &mut MemoryController) -> bool {
+    if !cpu::has(Features::APIC) {
+        return false;
+    }
+
+    let mode = if cpu::has(Features::X2APIC) {
+        Mode::X2Apic
+    } else {
+        let address = if address != 0 {
+            address
+        } else {
+            (unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_ADDRESS_MASK) as PhysicalAddress
+        };
+        match memory_controller.ioremap(address, PAGE_SIZE) {
+            Some(base) => Mode::XApic(base),
+            None => return false,
+        }
+    };
+    let apic = LOCAL_APIC.call_once(|| LocalApic{ mode: mode });
+    apic.enable();
+    println!("local APIC {} enabled in {:?} mode", apic.id(), apic.mode);
+    true
+}
+
+// For application processors, the bootstrap processor has to have called `init`
+pub fn init_ap() {
+    local().enable();
+}
+
+pub fn local() -> &'static LocalApic {
+    LOCAL_APIC.try().expect("local APIC not initialized")
+}
+
+pub fn enabled() -> bool {
+    LOCAL_APIC.try().is_some()
+}
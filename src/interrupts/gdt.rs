@@ -0,0 +1,201 @@
+use core::mem::size_of;
+
+use memory::VirtualAddress;
+use smp::MAX_CPUS;
+
+// Selectors of the segments in `GDT`. The user selectors are used with RPL 3. The order of the
+// user data and code segments is the one `sysret` expects.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
+
+// Interrupt stack table slots, see `EntryOptions::set_stack_index`
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+pub const IST_STACK_SIZE: usize = 4096 * 4;
+const GDT_ENTRIES: usize = 7;
+const TSS_SIZE: u16 = 104;
+
+/**
+Bits of a code or data segment descriptor. Apart from these, long mode ignores base and limit.
+    Bit(s)  Name            Meaning
+    41      writable        data segments only, `sysret` requires it for SS
+    43      executable      code segment
+    44      user segment    code or data segment, as opposed to a system segment like the TSS
+    45-46   privilege level ring of the segment
+    47      present
+    53      long mode       64-bit code segment
+**/
+const WRITABLE: u64 = 1 << 41;
+const EXECUTABLE: u64 = 1 << 43;
+const USER_SEGMENT: u64 = 1 << 44;
+const RING_3: u64 = 3 << 45;
+const PRESENT: u64 = 1 << 47;
+const LONG_MODE: u64 = 1 << 53;
+// Type of an available 64-bit TSS in a system segment descriptor
+const TSS_AVAILABLE: u64 = 0b1001 << 40;
+
+/**
+The 64-bit task state segment. It no longer holds any task state, only the stacks the CPU
+switches to: `privilege_stack_table[n]` on an interrupt from a lower privilege level into ring
+n and `interrupt_stack_table[n]` for interrupts whose IDT entry selects IST slot n.
+**/
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved_1: u32,
+    pub privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    pub interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> TaskStateSegment {
+        TaskStateSegment{
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            // No I/O permission bitmap
+            iomap_base: TSS_SIZE,
+        }
+    }
+}
+
+// Stacks of the bootstrap processor for the exceptions that can hit while the current stack
+// is unusable. u64 elements keep them 8 byte aligned. APs get theirs from `smp`.
+struct IstStack([u64; IST_STACK_SIZE / 8]);
+
+static mut IST_STACKS: [IstStack; 3] = [
+    IstStack([0; IST_STACK_SIZE / 8]),
+    IstStack([0; IST_STACK_SIZE / 8]),
+    IstStack([0; IST_STACK_SIZE / 8]),
+];
+
+// Each CPU needs its own TSS (for its stacks and because loading marks it busy) and thus its
+// own GDT. Entry n is only touched by CPU n.
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [TaskStateSegment::new(); MAX_CPUS];
+static mut GDT: [Gdt; MAX_CPUS] = [Gdt::new(); MAX_CPUS];
+
+#[derive(Clone, Copy)]
+struct Gdt {
+    table: [u64; GDT_ENTRIES],
+    next_free: usize,
+}
+
+impl Gdt {
+    const fn new() -> Gdt {
+        Gdt{
+            // The first entry has to be the null descriptor
+            table: [0; GDT_ENTRIES],
+            next_free: 1,
+        }
+    }
+
+    // Returns the selector of the added segment
+    fn add_entry(&mut self, descriptor: u64) -> u16 {
+        let index = self.next_free;
+        self.table[index] = descriptor;
+        self.next_free += 1;
+        (index * 8) as u16
+    }
+
+    // The TSS descriptor takes two entries since it holds a 64-bit base address
+    fn add_tss(&mut self, tss: &'static TaskStateSegment) -> u16 {
+        let base = tss as *const _ as u64;
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+        let low = PRESENT | TSS_AVAILABLE | (limit & 0xffff) | ((base & 0xff_ffff) << 16)
+            | (((base >> 24) & 0xff) << 56);
+        let selector = self.add_entry(low);
+        self.add_entry(base >> 32);
+        selector
+    }
+
+    fn load(&'static self) {
+        let pointer = DescriptorTablePointer{
+            limit: (size_of::<[u64; GDT_ENTRIES]>() - 1) as u16,
+            base: self.table.as_ptr() as u64,
+        };
+        unsafe { asm!("lgdt ($0)" :: "r"(&pointer) : "memory" : "volatile") };
+    }
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+// Sets the stack CPU number `cpu` switches to on interrupts from user mode
+pub fn set_privilege_stack(cpu: usize, top: VirtualAddress) {
+    unsafe { TSS[cpu].privilege_stack_table[0] = top as u64 & !0xf };
+}
+
+// Tops of the double fault, NMI and machine check stacks of CPU number `cpu`, 0 before
+// `init_cpu` ran for it
+pub fn ist_stack_tops(cpu: usize) -> [VirtualAddress; 3] {
+    let tss = unsafe { &TSS[cpu] };
+    let indices = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX];
+    let mut tops = [0; 3];
+    for (top, &index) in tops.iter_mut().zip(indices.iter()) {
+        *top = tss.interrupt_stack_table[index as usize] as VirtualAddress;
+    }
+    tops
+}
+
+// Sets up the GDT and TSS of the bootstrap processor
+pub fn init() {
+    let mut stacks = [0; 3];
+    for (top, stack) in stacks.iter_mut().zip(unsafe { IST_STACKS.iter() }) {
+        // Stacks grow down, so the CPU gets the end of the array
+        *top = stack.0.as_ptr() as VirtualAddress + IST_STACK_SIZE;
+    }
+    init_cpu(0, stacks);
+}
+
+// Builds and loads the GDT and TSS of CPU number `cpu`. `ist_stacks` are the tops of its
+// double fault, NMI and machine check stacks.
+pub fn init_cpu(cpu: usize, ist_stacks: [VirtualAddress; 3]) {
+    assert!(size_of::<TaskStateSegment>() == TSS_SIZE as usize);
+    let tss = unsafe { &mut TSS[cpu] };
+    let indices = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX];
+    for (&top, &index) in ist_stacks.iter().zip(indices.iter()) {
+        tss.interrupt_stack_table[index as usize] = top as u64 & !0xf;
+    }
+
+    let gdt = unsafe { &mut GDT[cpu] };
+    *gdt = Gdt::new();
+    let kernel_code = gdt.add_entry(USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE);
+    let kernel_data = gdt.add_entry(USER_SEGMENT | PRESENT | WRITABLE);
+    let user_data = gdt.add_entry(USER_SEGMENT | PRESENT | WRITABLE | RING_3);
+    let user_code = gdt.add_entry(USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE | RING_3);
+    let tss_selector = gdt.add_tss(tss);
+    assert!(kernel_code == KERNEL_CODE_SELECTOR && kernel_data == KERNEL_DATA_SELECTOR);
+    assert!(user_data | 3 == USER_DATA_SELECTOR && user_code | 3 == USER_CODE_SELECTOR);
+    assert!(tss_selector == TSS_SELECTOR);
+    gdt.load();
+
+    unsafe {
+        // CS can't be moved into directly, reload it with a far return to the next instruction
+        asm!("pushq $0
+              leaq 1f(%rip), %rax
+              pushq %rax
+              lretq
+              1:"
+             :: "i"(KERNEL_CODE_SELECTOR as u64) : "rax", "memory" : "volatile");
+        asm!("movw $0, %ds
+              movw $0, %es
+              movw $0, %ss"
+             :: "r"(KERNEL_DATA_SELECTOR) :: "volatile");
+        asm!("ltr $0" :: "r"(TSS_SELECTOR) :: "volatile");
+    }
+}
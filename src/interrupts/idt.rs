@@ -0,0 +1,154 @@
+use core::mem::size_of;
+
+// Handler for vectors that don't push an error code
+pub type HandlerFunc = extern "x86-interrupt" fn(&mut ExceptionStackFrame);
+// Handler for vectors that push an error code (8, 10-14, 17, 21, 29, 30)
+pub type HandlerFuncWithErrCode = extern "x86-interrupt" fn(&mut ExceptionStackFrame, u64);
+
+pub const ENTRY_COUNT: usize = 256;
+
+/**
+What the CPU pushes on the stack before calling a handler. Handlers receive a pointer to it
+and `iretq` restores it, so changes (e.g. to the instruction pointer) take effect on return.
+**/
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionStackFrame {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+}
+
+/**
+The bit layout of an IDT entry is as follows:
+    Bit(s)  Name                        Meaning
+    0-15    function pointer [0:15]     the lower bits of the pointer to the handler function
+    16-31   GDT selector                selector of a code segment in the GDT
+    32-47   options                     see `EntryOptions`
+    48-63   function pointer [16:31]    the middle bits of the pointer to the handler function
+    64-95   function pointer [32:63]    the remaining bits of the pointer to the handler function
+    96-127  reserved
+**/
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Entry {
+    pointer_low: u16,
+    gdt_selector: u16,
+    options: EntryOptions,
+    pointer_middle: u16,
+    pointer_high: u32,
+    reserved: u32,
+}
+
+impl Entry {
+    const fn missing() -> Entry {
+        Entry{
+            pointer_low: 0,
+            gdt_selector: 0,
+            options: EntryOptions::minimal(),
+            pointer_middle: 0,
+            pointer_high: 0,
+            reserved: 0,
+        }
+    }
+
+    fn set_handler_addr(&mut self, address: u64) -> &mut EntryOptions {
+        self.pointer_low = address as u16;
+        self.pointer_middle = (address >> 16) as u16;
+        self.pointer_high = (address >> 32) as u32;
+        self.gdt_selector = code_segment();
+        self.options = EntryOptions::minimal();
+        self.options.set_present(true);
+        &mut self.options
+    }
+}
+
+/**
+The bit layout of the options field is as follows:
+    Bit(s)  Name                        Meaning
+    0-2     interrupt stack table index 0: don't switch stacks, 1-7: switch to the nth IST stack
+    3-7     reserved
+    8       0: interrupt gate           if this bit is 0, interrupts are disabled on entry
+            1: trap gate
+    9-11    must be one
+    12      must be zero
+    13-14   descriptor privilege level  minimal ring required to call this handler via `int`
+    15      present
+**/
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EntryOptions(u16);
+
+impl EntryOptions {
+    const fn minimal() -> EntryOptions {
+        EntryOptions(0b1110_0000_0000)
+    }
+
+    pub fn set_present(&mut self, present: bool) -> &mut EntryOptions {
+        self.set_bit(15, present)
+    }
+
+    pub fn disable_interrupts(&mut self, disable: bool) -> &mut EntryOptions {
+        self.set_bit(8, !disable)
+    }
+
+    pub fn set_privilege_level(&mut self, dpl: u16) -> &mut EntryOptions {
+        self.0 = (self.0 & !(0b11 << 13)) | ((dpl & 0b11) << 13);
+        self
+    }
+
+    // `index` is the IST slot starting at 0 (the entry stores it plus one)
+    pub fn set_stack_index(&mut self, index: u16) -> &mut EntryOptions {
+        self.0 = (self.0 & !0b111) | ((index + 1) & 0b111);
+        self
+    }
+
+    fn set_bit(&mut self, bit: u16, value: bool) -> &mut EntryOptions {
+        if value {
+            self.0 |= 1 << bit;
+        } else {
+            self.0 &= !(1 << bit);
+        }
+        self
+    }
+}
+
+pub struct Idt([Entry; ENTRY_COUNT]);
+
+impl Idt {
+    pub fn new() -> Idt {
+        Idt([Entry::missing(); ENTRY_COUNT])
+    }
+
+    pub fn set_handler(&mut self, vector: u8, handler: HandlerFunc) -> &mut EntryOptions {
+        self.0[vector as usize].set_handler_addr(handler as u64)
+    }
+
+    pub fn set_handler_with_error_code(&mut self, vector: u8, handler: HandlerFuncWithErrCode)
+        -> &mut EntryOptions
+    {
+        self.0[vector as usize].set_handler_addr(handler as u64)
+    }
+
+    pub fn load(&'static self) {
+        let pointer = DescriptorTablePointer{
+            limit: (size_of::<Self>() - 1) as u16,
+            base: self as *const _ as u64,
+        };
+        unsafe { asm!("lidt ($0)" :: "r"(&pointer) : "memory" : "volatile") };
+    }
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+fn code_segment() -> u16 {
+    let segment: u16;
+    unsafe { asm!("mov %cs, $0" : "=r"(segment) ::: "volatile") };
+    segment
+}
@@ -0,0 +1,158 @@
+use core::ptr;
+
+use acpi::{self, Madt};
+use memory::{MemoryController, VirtualAddress};
+use sync::IrqLock;
+
+const MAX_IO_APICS: usize = 8;
+
+// Register select and data window in the MMIO area
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// Indirect registers
+const IOAPICVER: u32 = 0x01;
+// Redirection entry n is at `IOREDTBL + 2 * n` (low half) and the register after it (high half)
+const IOREDTBL: u32 = 0x10;
+
+/**
+The bit layout of a redirection entry is as follows:
+    Bit(s)  Name                Meaning
+    0-7     vector              the interrupt vector raised on the destination CPU
+    8-10    delivery mode       0: fixed
+    11      destination mode    0: physical (APIC id), 1: logical
+    12      delivery status     read only
+    13      polarity            0: active high, 1: active low
+    14      remote IRR          read only
+    15      trigger mode        0: edge, 1: level
+    16      mask                masked interrupts are not delivered
+    56-63   destination         APIC id of the target CPU
+**/
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+const DESTINATION_SHIFT: u64 = 56;
+
+static IO_APICS: IrqLock<[Option<IoApic>; MAX_IO_APICS]> = IrqLock::new([None; MAX_IO_APICS]);
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    base: VirtualAddress,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn redirection(&self, index: u32) -> u64 {
+        let low = self.read(IOREDTBL + 2 * index) as u64;
+        let high = self.read(IOREDTBL + 2 * index + 1) as u64;
+        high << 32 | low
+    }
+
+    fn set_redirection(&self, index: u32, entry: u64) {
+        // Mask the entry while it's half written
+        self.write(IOREDTBL + 2 * index, MASKED as u32);
+        self.write(IOREDTBL + 2 * index + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + 2 * index, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+}
+
+// Maps every I/O APIC in the MADT and masks all of their inputs
+pub fn init(madt: &Madt, memory_controller: &mut MemoryController) -> bool {
+    let mut io_apics = IO_APICS.lock();
+    let mut count = 0;
+    for entry in madt.io_apics().iter().take(MAX_IO_APICS) {
+        let base = match memory_controller.ioremap(entry.address, IOWIN + 4) {
+            Some(base) => base,
+            None => continue,
+        };
+        let mut io_apic = IoApic{
+            base: base,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        // Bits 16-23 hold the index of the last redirection entry
+        io_apic.entries = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        for index in 0..io_apic.entries {
+            io_apic.set_redirection(index, MASKED);
+        }
+        println!("I/O APIC {} handles GSIs {}-{}", entry.id, io_apic.gsi_base,
+                 io_apic.gsi_base + io_apic.entries - 1);
+        io_apics[count] = Some(io_apic);
+        count += 1;
+    }
+    count > 0
+}
+
+// Routes `gsi` to `vector` on the CPU with `apic_id`. The entry stays masked (or unmasked) as
+// it was. Returns false if no I/O APIC handles the GSI.
+pub fn route(gsi: u32, vector: u8, apic_id: u32, active_low: bool, level_triggered: bool)
+    -> bool
+{
+    with_entry(gsi, |entry| {
+        let mut new = (entry & MASKED) | vector as u64 | (apic_id as u64) << DESTINATION_SHIFT;
+        if active_low {
+            new |= ACTIVE_LOW;
+        }
+        if level_triggered {
+            new |= LEVEL_TRIGGERED;
+        }
+        new
+    })
+}
+
+// Routes ISA IRQ `line` like `route` does, applying the MADT interrupt source overrides
+pub fn route_isa(line: u8, vector: u8, apic_id: u32) -> bool {
+    let irq = match acpi::madt().and_then(|madt| madt.isa_irq(line)) {
+        Some(irq) => irq,
+        None => return false,
+    };
+    route(irq.gsi, vector, apic_id, irq.active_low, irq.level_triggered)
+}
+
+pub fn mask(gsi: u32) -> bool {
+    with_entry(gsi, |entry| entry | MASKED)
+}
+
+pub fn unmask(gsi: u32) -> bool {
+    with_entry(gsi, |entry| entry & !MASKED)
+}
+
+// GSI that ISA IRQ `line` is connected to
+pub fn isa_gsi(line: u8) -> Option<u32> {
+    acpi::madt().and_then(|madt| madt.isa_irq(line)).map(|irq| irq.gsi)
+}
+
+fn with_entry<F>(gsi: u32, f: F) -> bool
+where
+    F: FnOnce(u64) -> u64,
+{
+    let io_apics = IO_APICS.lock();
+    match io_apics.iter().filter_map(|io_apic| *io_apic).find(|io_apic| io_apic.handles(gsi)) {
+        Some(io_apic) => {
+            let index = gsi - io_apic.gsi_base;
+            let entry = io_apic.redirection(index);
+            io_apic.set_redirection(index, f(entry));
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,469 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use spin::Once;
+
+use acpi;
+use memory::{self, MemoryController};
+use percpu::{self, KernelGs};
+use sync::IrqLock;
+use user;
+use self::idt::{Idt, ExceptionStackFrame};
+
+pub mod apic;
+pub mod gdt;
+mod idt;
+mod ioapic;
+mod pic;
+
+static IDT: Once<Idt> = Once::new();
+
+// Handlers drivers registered for the legacy IRQ lines
+static IRQ_HANDLERS: IrqLock<[Option<fn()>; pic::IRQ_COUNT as usize]> =
+    IrqLock::new([None; pic::IRQ_COUNT as usize]);
+
+// Vectors for interrupts raised by the local APIC itself, like its timer or IPIs
+pub const LOCAL_VECTOR_BASE: u8 = 0xf0;
+const LOCAL_VECTOR_COUNT: usize = 8;
+
+static LOCAL_HANDLERS: IrqLock<[Option<fn()>; LOCAL_VECTOR_COUNT]> =
+    IrqLock::new([None; LOCAL_VECTOR_COUNT]);
+
+per_cpu! {
+    // Number of interrupt handlers running on this CPU, nested ones included
+    static IRQ_DEPTH: usize = 0;
+}
+
+// Set once IRQs are delivered through the I/O APIC instead of the 8259 PICs
+static APIC_MODE: AtomicBool = ATOMIC_BOOL_INIT;
+
+// The interrupt flag in RFLAGS
+const RFLAGS_IF: u64 = 1 << 9;
+
+// Names of the architecturally defined exceptions, indexed by vector
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK-SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "x87 FLOATING-POINT EXCEPTION",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING-POINT EXCEPTION",
+    "VIRTUALIZATION EXCEPTION",
+    "CONTROL PROTECTION EXCEPTION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION EXCEPTION",
+    "VMM COMMUNICATION EXCEPTION",
+    "SECURITY EXCEPTION",
+    "RESERVED",
+];
+
+// Page fault error code bit that is set for protection violations and clear for non-present pages
+const PAGE_FAULT_PROTECTION_VIOLATION: u64 = 1 << 0;
+
+// Handlers for fatal exceptions: report and halt
+macro_rules! exception {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            let _gs = kernel_gs($vector, stack_frame);
+            report($vector, None, stack_frame);
+            kill_or_halt($vector, stack_frame);
+        }
+    };
+}
+
+macro_rules! exception_with_error_code {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+            let _gs = kernel_gs($vector, stack_frame);
+            report($vector, Some(error_code), stack_frame);
+            kill_or_halt($vector, stack_frame);
+        }
+    };
+}
+
+exception!(divide_error, 0);
+exception!(debug, 1);
+exception!(non_maskable_interrupt, 2);
+exception!(overflow, 4);
+exception!(bound_range_exceeded, 5);
+exception!(invalid_opcode, 6);
+exception!(device_not_available, 7);
+exception_with_error_code!(double_fault, 8);
+exception!(coprocessor_segment_overrun, 9);
+exception_with_error_code!(invalid_tss, 10);
+exception_with_error_code!(segment_not_present, 11);
+exception_with_error_code!(stack_segment_fault, 12);
+exception_with_error_code!(general_protection_fault, 13);
+exception!(reserved_15, 15);
+exception!(x87_floating_point, 16);
+exception_with_error_code!(alignment_check, 17);
+exception!(machine_check, 18);
+exception!(simd_floating_point, 19);
+exception!(virtualization, 20);
+exception_with_error_code!(control_protection, 21);
+exception!(reserved_22, 22);
+exception!(reserved_23, 23);
+exception!(reserved_24, 24);
+exception!(reserved_25, 25);
+exception!(reserved_26, 26);
+exception!(reserved_27, 27);
+exception!(hypervisor_injection, 28);
+exception_with_error_code!(vmm_communication, 29);
+exception_with_error_code!(security_exception, 30);
+exception!(reserved_31, 31);
+
+// Handlers for the remapped IRQ vectors, they all go through the dispatch table
+macro_rules! irq {
+    ($name:ident, $line:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            let _gs = KernelGs::enter(from_user(stack_frame));
+            dispatch_irq($line);
+        }
+    };
+}
+
+irq!(irq_0, 0);
+irq!(irq_1, 1);
+irq!(irq_2, 2);
+irq!(irq_3, 3);
+irq!(irq_4, 4);
+irq!(irq_5, 5);
+irq!(irq_6, 6);
+irq!(irq_7, 7);
+irq!(irq_8, 8);
+irq!(irq_9, 9);
+irq!(irq_10, 10);
+irq!(irq_11, 11);
+irq!(irq_12, 12);
+irq!(irq_13, 13);
+irq!(irq_14, 14);
+irq!(irq_15, 15);
+
+macro_rules! local_interrupt {
+    ($name:ident, $index:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+            let _gs = KernelGs::enter(from_user(stack_frame));
+            dispatch_local_interrupt($index);
+        }
+    };
+}
+
+local_interrupt!(local_0, 0);
+local_interrupt!(local_1, 1);
+local_interrupt!(local_2, 2);
+local_interrupt!(local_3, 3);
+local_interrupt!(local_4, 4);
+local_interrupt!(local_5, 5);
+local_interrupt!(local_6, 6);
+local_interrupt!(local_7, 7);
+
+pub fn init() {
+    // The IST indices below refer to the TSS, so it has to be loaded first
+    gdt::init();
+
+    let idt = IDT.call_once(|| {
+        let mut idt = Idt::new();
+        idt.set_handler(0, divide_error);
+        idt.set_handler(1, debug);
+        idt.set_handler(2, non_maskable_interrupt)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.set_handler(3, breakpoint);
+        idt.set_handler(4, overflow);
+        idt.set_handler(5, bound_range_exceeded);
+        idt.set_handler(6, invalid_opcode);
+        idt.set_handler(7, device_not_available);
+        idt.set_handler_with_error_code(8, double_fault)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.set_handler(9, coprocessor_segment_overrun);
+        idt.set_handler_with_error_code(10, invalid_tss);
+        idt.set_handler_with_error_code(11, segment_not_present);
+        idt.set_handler_with_error_code(12, stack_segment_fault);
+        idt.set_handler_with_error_code(13, general_protection_fault);
+        idt.set_handler_with_error_code(14, page_fault);
+        idt.set_handler(15, reserved_15);
+        idt.set_handler(16, x87_floating_point);
+        idt.set_handler_with_error_code(17, alignment_check);
+        idt.set_handler(18, machine_check)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.set_handler(19, simd_floating_point);
+        idt.set_handler(20, virtualization);
+        idt.set_handler_with_error_code(21, control_protection);
+        idt.set_handler(22, reserved_22);
+        idt.set_handler(23, reserved_23);
+        idt.set_handler(24, reserved_24);
+        idt.set_handler(25, reserved_25);
+        idt.set_handler(26, reserved_26);
+        idt.set_handler(27, reserved_27);
+        idt.set_handler(28, hypervisor_injection);
+        idt.set_handler_with_error_code(29, vmm_communication);
+        idt.set_handler_with_error_code(30, security_exception);
+        idt.set_handler(31, reserved_31);
+
+        let irqs: [idt::HandlerFunc; pic::IRQ_COUNT as usize] = [
+            irq_0, irq_1, irq_2, irq_3, irq_4, irq_5, irq_6, irq_7,
+            irq_8, irq_9, irq_10, irq_11, irq_12, irq_13, irq_14, irq_15];
+        for (line, &handler) in irqs.iter().enumerate() {
+            idt.set_handler(pic::IRQ_BASE + line as u8, handler);
+        }
+        let locals: [idt::HandlerFunc; LOCAL_VECTOR_COUNT] = [
+            local_0, local_1, local_2, local_3, local_4, local_5, local_6, local_7];
+        for (index, &handler) in locals.iter().enumerate() {
+            idt.set_handler(LOCAL_VECTOR_BASE + index as u8, handler);
+        }
+        idt.set_handler(apic::SPURIOUS_INTERRUPT_VECTOR, spurious_interrupt);
+        idt
+    });
+    idt.load();
+
+    pic::init();
+}
+
+// Loads the shared IDT and the GDT and TSS of an application processor and enables its local
+// APIC. `ist_stacks` as for `gdt::init_cpu`.
+pub fn init_ap(cpu: usize, ist_stacks: [memory::VirtualAddress; 3]) {
+    gdt::init_cpu(cpu, ist_stacks);
+    IDT.try().expect("IDT not initialized").load();
+    if apic::enabled() {
+        apic::init_ap();
+    }
+}
+
+// Moves IRQ delivery from the PICs to the local and I/O APICs described by the ACPI MADT. The
+// ISA IRQs keep their vectors and initially go to the bootstrap processor. Returns false (and
+// keeps using the PICs) if there is no APIC.
+pub fn init_apic(memory_controller: &mut MemoryController) -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    if !apic::init(madt.local_apic_address, memory_controller)
+        || !ioapic::init(madt, memory_controller)
+    {
+        println!("no usable APIC, staying with the 8259 PICs");
+        return false;
+    }
+
+    without_interrupts(|| {
+        let bsp = apic::local().id();
+        let handlers = IRQ_HANDLERS.lock();
+        for line in 0..pic::IRQ_COUNT {
+            ioapic::route_isa(line, pic::IRQ_BASE + line, bsp);
+            if handlers[line as usize].is_some() {
+                unmask_irq(line, true);
+            }
+        }
+        if madt.has_pic {
+            pic::disable();
+        }
+        APIC_MODE.store(true, Ordering::SeqCst);
+    });
+    true
+}
+
+// Delivers IRQ `line` to the CPU with `apic_id` from now on. Only possible with the I/O APIC.
+pub fn set_irq_affinity(line: u8, apic_id: u32) -> bool {
+    assert!(line < pic::IRQ_COUNT, "invalid IRQ line");
+    APIC_MODE.load(Ordering::SeqCst) && ioapic::route_isa(line, pic::IRQ_BASE + line, apic_id)
+}
+
+// Installs `handler` for the legacy IRQ `line` and unmasks it. The handler runs with
+// interrupts disabled and the EOI is sent after it returns.
+pub fn register_irq(line: u8, handler: fn()) {
+    assert!(line < pic::IRQ_COUNT, "invalid IRQ line");
+    let mut handlers = IRQ_HANDLERS.lock();
+    assert!(handlers[line as usize].is_none(), "IRQ line already has a handler");
+    handlers[line as usize] = Some(handler);
+    unmask_irq(line, APIC_MODE.load(Ordering::SeqCst));
+}
+
+pub fn unregister_irq(line: u8) {
+    assert!(line < pic::IRQ_COUNT, "invalid IRQ line");
+    let mut handlers = IRQ_HANDLERS.lock();
+    mask_irq(line, APIC_MODE.load(Ordering::SeqCst));
+    handlers[line as usize] = None;
+}
+
+// Installs `handler` for one of the local APIC vectors starting at `LOCAL_VECTOR_BASE`. The
+// caller programs the interrupt source, the EOI is sent after the handler returns.
+pub fn register_local_interrupt(vector: u8, handler: fn()) {
+    let index = vector.wrapping_sub(LOCAL_VECTOR_BASE) as usize;
+    assert!(index < LOCAL_VECTOR_COUNT, "not a local APIC vector");
+    LOCAL_HANDLERS.lock()[index] = Some(handler);
+}
+
+fn unmask_irq(line: u8, apic_mode: bool) {
+    if apic_mode {
+        if let Some(gsi) = ioapic::isa_gsi(line) {
+            ioapic::unmask(gsi);
+        }
+    } else {
+        pic::unmask(line);
+    }
+}
+
+fn mask_irq(line: u8, apic_mode: bool) {
+    if apic_mode {
+        if let Some(gsi) = ioapic::isa_gsi(line) {
+            ioapic::mask(gsi);
+        }
+    } else {
+        pic::mask(line);
+    }
+}
+
+pub fn enable() {
+    unsafe { asm!("sti" :::: "volatile") };
+}
+
+pub fn disable() {
+    unsafe { asm!("cli" :::: "volatile") };
+}
+
+pub fn enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(flags) ::: "volatile") };
+    flags & RFLAGS_IF != 0
+}
+
+// Disables interrupts and returns whether they were enabled, for `restore`
+pub fn save_and_disable() -> bool {
+    let enabled = enabled();
+    disable();
+    enabled
+}
+
+pub fn restore(enabled: bool) {
+    if enabled {
+        enable();
+    }
+}
+
+// Runs `f` with interrupts disabled and restores the previous state afterwards
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let enabled = save_and_disable();
+    let result = f();
+    restore(enabled);
+    result
+}
+
+// Whether the calling code runs in a hardware interrupt handler
+pub fn in_interrupt() -> bool {
+    IRQ_DEPTH.get() > 0
+}
+
+fn dispatch_irq(line: u8) {
+    let apic_mode = APIC_MODE.load(Ordering::Relaxed);
+    if !apic_mode && pic::is_spurious(line) {
+        return;
+    }
+    IRQ_DEPTH.with(|depth| *depth += 1);
+    // Copy the handler out so that it can (un)register handlers itself
+    let handler = IRQ_HANDLERS.lock()[line as usize];
+    match handler {
+        Some(handler) => handler(),
+        None => println!("unhandled IRQ {}", line),
+    }
+    if apic_mode {
+        apic::local().end_of_interrupt();
+    } else {
+        pic::end_of_interrupt(line);
+    }
+    IRQ_DEPTH.with(|depth| *depth -= 1);
+}
+
+fn dispatch_local_interrupt(index: usize) {
+    IRQ_DEPTH.with(|depth| *depth += 1);
+    let handler = LOCAL_HANDLERS.lock()[index];
+    match handler {
+        Some(handler) => handler(),
+        None => println!("unhandled local interrupt {:#x}", LOCAL_VECTOR_BASE + index as u8),
+    }
+    apic::local().end_of_interrupt();
+    IRQ_DEPTH.with(|depth| *depth -= 1);
+}
+
+// Whether the interrupted code ran in ring 3
+fn from_user(stack_frame: &ExceptionStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+// NMIs, double faults and machine checks can also hit kernel code running with the user GS base,
+// right after `syscall` or right before returning to user mode, so for them the GS base itself
+// decides
+fn kernel_gs(vector: usize, stack_frame: &ExceptionStackFrame) -> KernelGs {
+    match vector {
+        2 | 8 | 18 => KernelGs::enter(!percpu::loaded()),
+        _ => KernelGs::enter(from_user(stack_frame)),
+    }
+}
+
+// Spurious APIC interrupts are not in service, so they must not get an EOI
+extern "x86-interrupt" fn spurious_interrupt(_stack_frame: &mut ExceptionStackFrame) {}
+
+// `int3` is used for debugging, so report it and carry on
+extern "x86-interrupt" fn breakpoint(stack_frame: &mut ExceptionStackFrame) {
+    let _gs = KernelGs::enter(from_user(stack_frame));
+    println!("\nEXCEPTION: {} at {:#x}", EXCEPTION_NAMES[3], stack_frame.instruction_pointer);
+}
+
+extern "x86-interrupt" fn page_fault(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    use x86_64::registers::control_regs;
+
+    let _gs = KernelGs::enter(from_user(stack_frame));
+    let address = control_regs::cr2().0;
+    // Non-present pages may have been swapped out, in which case the access is simply retried
+    if error_code & PAGE_FAULT_PROTECTION_VIOLATION == 0 && memory::handle_page_fault(address) {
+        return;
+    }
+    report(14, Some(error_code), stack_frame);
+    println!("    CR2:    {:#018x}", address);
+    kill_or_halt(14, stack_frame);
+}
+
+fn report(vector: usize, error_code: Option<u64>, stack_frame: &ExceptionStackFrame) {
+    println!("\nEXCEPTION: {} (vector {})", EXCEPTION_NAMES[vector], vector);
+    if let Some(error_code) = error_code {
+        println!("    error code: {:#x}", error_code);
+    }
+    println!("    RIP:    {:#018x}", stack_frame.instruction_pointer);
+    println!("    CS:     {:#x}", stack_frame.code_segment);
+    println!("    RFLAGS: {:#018x}", stack_frame.cpu_flags);
+    println!("    RSP:    {:#018x}", stack_frame.stack_pointer);
+    println!("    SS:     {:#x}", stack_frame.stack_segment);
+}
+
+// Exceptions caused by a user program only end it. NMIs, double faults and machine checks
+// aren't the program's fault and stay fatal.
+fn kill_or_halt(vector: usize, stack_frame: &ExceptionStackFrame) -> ! {
+    let from_user = stack_frame.code_segment & 3 == 3;
+    if from_user && vector != 2 && vector != 8 && vector != 18 {
+        user::exit(user::Exit::Killed(vector as u8));
+    }
+    halt()
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile") };
+    }
+}
@@ -0,0 +1,112 @@
+use x86_64::instructions::port::{inb, outb};
+
+// First vector the 16 legacy IRQs are remapped to. Vectors below 32 belong to CPU exceptions.
+pub const IRQ_BASE: u8 = 32;
+pub const IRQ_COUNT: u8 = 16;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+// ICW1: start initialization, an ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+// ICW4: 8086 mode
+const ICW4_8086: u8 = 0x01;
+// OCW2: non-specific end of interrupt
+const EOI: u8 = 0x20;
+// OCW3: read the in-service register on the next command port read
+const READ_ISR: u8 = 0x0b;
+
+// The slave PIC is connected to line 2 of the master
+const CASCADE_LINE: u8 = 2;
+
+// Remaps both PICs to `IRQ_BASE` and masks every line
+pub fn init() {
+    unsafe {
+        outb(MASTER_COMMAND, ICW1_INIT);
+        io_wait();
+        outb(SLAVE_COMMAND, ICW1_INIT);
+        io_wait();
+        outb(MASTER_DATA, IRQ_BASE);
+        io_wait();
+        outb(SLAVE_DATA, IRQ_BASE + 8);
+        io_wait();
+        outb(MASTER_DATA, 1 << CASCADE_LINE);
+        io_wait();
+        outb(SLAVE_DATA, CASCADE_LINE);
+        io_wait();
+        outb(MASTER_DATA, ICW4_8086);
+        io_wait();
+        outb(SLAVE_DATA, ICW4_8086);
+        io_wait();
+
+        // Unmasked lines are enabled by `unmask`, only the cascade has to go through
+        outb(MASTER_DATA, !(1 << CASCADE_LINE));
+        outb(SLAVE_DATA, 0xff);
+    }
+}
+
+// Masks every line once the APIC takes over. The PICs stay remapped, so spurious interrupts
+// they might still raise don't land on exception vectors.
+pub fn disable() {
+    unsafe {
+        outb(MASTER_DATA, 0xff);
+        outb(SLAVE_DATA, 0xff);
+    }
+}
+
+pub fn mask(line: u8) {
+    let (port, bit) = data_port(line);
+    unsafe { outb(port, inb(port) | 1 << bit) };
+}
+
+pub fn unmask(line: u8) {
+    let (port, bit) = data_port(line);
+    unsafe { outb(port, inb(port) & !(1 << bit)) };
+}
+
+pub fn end_of_interrupt(line: u8) {
+    assert!(line < IRQ_COUNT);
+    unsafe {
+        if line >= 8 {
+            outb(SLAVE_COMMAND, EOI);
+        }
+        outb(MASTER_COMMAND, EOI);
+    }
+}
+
+// Line 7 and 15 fire spuriously when an IRQ disappears before it's acknowledged. Those must not
+// get an EOI, except for the cascade line on the master if the slave sent it.
+pub fn is_spurious(line: u8) -> bool {
+    let spurious = match line {
+        7 => in_service(MASTER_COMMAND) & 1 << 7 == 0,
+        15 => in_service(SLAVE_COMMAND) & 1 << 7 == 0,
+        _ => false,
+    };
+    if spurious && line == 15 {
+        unsafe { outb(MASTER_COMMAND, EOI) };
+    }
+    spurious
+}
+
+fn in_service(command_port: u16) -> u8 {
+    unsafe {
+        outb(command_port, READ_ISR);
+        inb(command_port)
+    }
+}
+
+fn data_port(line: u8) -> (u16, u8) {
+    assert!(line < IRQ_COUNT, "invalid IRQ line");
+    if line < 8 {
+        (MASTER_DATA, line)
+    } else {
+        (SLAVE_DATA, line - 8)
+    }
+}
+
+// Writing to an unused port takes long enough for the PIC to process the previous command
+fn io_wait() {
+    unsafe { outb(0x80, 0) };
+}
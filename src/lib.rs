@@ -2,6 +2,10 @@
 #![feature(unique)]
 #![feature(const_unique_new)]
 #![feature(const_fn)]
+#![feature(asm)]
+#![feature(abi_x86_interrupt)]
+#![feature(repr_align)]
+#![feature(attr_literals)]
 #![no_std]
 #![allow(dead_code)]
 extern crate rlibc;
@@ -14,63 +18,121 @@ extern crate x86_64;
 
 #[macro_use]
 mod vga_buffer;
+mod serial;
+#[macro_use]
+mod percpu;
+mod sync;
+mod cpu;
 mod memory;
+mod block;
+mod interrupts;
+mod acpi;
+mod time;
+mod smp;
+mod syscall;
+mod user;
+mod backtrace;
+
+use spin::Once;
+
+use sync::Mutex;
 
-use memory::FrameAllocator;
+// Swap device until there is a real block device driver
+static SWAP_RAMDISK: Once<Mutex<block::RamDisk>> = Once::new();
+const SWAP_RAMDISK_SIZE: usize = 64 * memory::PAGE_SIZE;
 
 #[no_mangle]
 pub extern fn rust_main(multiboot_information_address: usize) {
+    serial::init();
     vga_buffer::clear_screen();
+    cpu::init();
+    interrupts::init();
+    // We are running in the higher half now, the identity map from boot.asm is no longer needed
+    memory::remove_identity_map();
+
+	let boot_info = unsafe{
+		multiboot2::load(memory::kernel_phys_to_virt(multiboot_information_address))
+	};
+    backtrace::init(boot_info);
+    memory::init(boot_info);
+    // The controller is only locked for short calls. Page faults on swapped out pages need it.
+    memory::with_controller(|memory_controller| memory_controller.test_paging());
+
+    // Interrupt handlers use per-CPU data, this has to happen before they are enabled
+    let area = memory::with_controller(|memory_controller| percpu::allocate(memory_controller))
+        .expect("no memory for per-CPU data");
+    unsafe { percpu::load(area, 0) };
+    memory::with_controller(|memory_controller| syscall::init(memory_controller));
+
+    let base = memory::with_controller(|memory_controller| {
+        memory_controller.vmalloc(SWAP_RAMDISK_SIZE)
+    }).expect("no memory for the swap RAM disk");
+    let disk = SWAP_RAMDISK.call_once(|| {
+        Mutex::new(unsafe { block::RamDisk::new(base, SWAP_RAMDISK_SIZE) })
+    });
+    memory::with_controller(|memory_controller| memory_controller.add_swap(disk));
+    memory::test_swap();
 
-	let boot_info = unsafe{ multiboot2::load(multiboot_information_address) };
-	let memory_map_tag = boot_info.memory_map_tag()
-		.expect("Memory map tag required");
-
-	println!("memory areas:");
-	for area in memory_map_tag.memory_areas() {
-		println!("    start: 0x{:x}, length: 0x{:x}",
-			area.base_addr, area.length);
-	}
-
-    let elf_sections_tag = boot_info.elf_sections_tag()
-        .expect("Elf-sections tag required");
-
-	//println!("kernel sections:");
-	//for section in elf_sections_tag.sections() {
-	//	println!("    addr: 0x{:x}, size: 0x{:x}, flags: 0x{:x}",
-	//		section.addr, section.size, section.flags);
-	//}
-
-	let kernel_start = elf_sections_tag.sections().map(|s| s.addr)
-		.min().unwrap();
-	let kernel_end = elf_sections_tag.sections().map(|s| s.addr + s.size)
-		.max().unwrap();
-
-	let multiboot_start = multiboot_information_address;
-	let multiboot_end = multiboot_start + (boot_info.total_size as usize);
-
-    println!("kernel start: 0x{:x}, kernel end: 0x{:x}", kernel_start, kernel_end);
-    println!("multiboot start: 0x{:x}, multiboot end: 0x{:x}", multiboot_start, multiboot_end);
-    let mut allocator = memory::Allocator::new(kernel_end as usize, multiboot_start as usize,
-                                               multiboot_end as usize);
-    println!("{:?}", &allocator as *const _);
-    println!("{:?}", allocator.allocate(1));
-    println!("{:?}", allocator.allocate(1));
-    println!("{:?}", allocator.allocate(2));
-    println!("{:?}", allocator.allocate(2));
-    memory::test_paging(&mut allocator);
+    memory::with_controller(|memory_controller| {
+        if acpi::init(memory_controller) {
+            interrupts::init_apic(memory_controller);
+        }
+        time::init(memory_controller);
+    });
+    memory::start_working_set_scanner();
+    smp::init();
+    user::test();
+
+    // Breakpoints are reported and execution continues
+    unsafe { asm!("int3" :::: "volatile") };
+    println!("It did not crash!");
+
+    // Every IRQ line stays masked until a driver registers a handler for it
+    interrupts::enable();
 
     loop{}
 }
 
+// Where application processors end up once `smp::init` started them
+pub fn ap_main(cpu: usize) -> ! {
+    println!("CPU {} (APIC id {}) online", cpu, percpu::apic_id());
+    interrupts::enable();
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
 #[no_mangle]
 #[lang = "eh_personality"]
 pub extern "C" fn eh_personality() {}
 
+// Prints to the screen and the serial port without waiting for locks the panic may hold
+macro_rules! panic_println {
+    ($fmt:expr) => ({
+        vga_buffer::print_panic(format_args!(concat!($fmt, "\n")));
+        serial::print_panic(format_args!(concat!($fmt, "\n")));
+    });
+    ($fmt:expr, $($arg:tt)*) => ({
+        vga_buffer::print_panic(format_args!(concat!($fmt, "\n"), $($arg)*));
+        serial::print_panic(format_args!(concat!($fmt, "\n"), $($arg)*));
+    });
+}
+
 #[no_mangle]
 #[lang = "panic_fmt"]
 pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
-    println!("\n\nPANIC in {} at line {}:", file, line);
-    println!("    {}", fmt);
+    interrupts::disable();
+    panic_println!("\n\nPANIC in {} at line {}:", file, line);
+    panic_println!("    {}", fmt);
+    panic_println!("backtrace:");
+    for (i, address) in backtrace::frames().enumerate() {
+        // Return addresses point after the call, which may already be the next function
+        match backtrace::symbolize(address - 1) {
+            Some((function, offset)) => {
+                panic_println!("    {:2}: {:#018x} {}+{:#x}", i, address, function, offset + 1)
+            }
+            None => panic_println!("    {:2}: {:#018x} <unknown>", i, address),
+        }
+    }
     loop{}
 }
@@ -35,7 +35,7 @@ impl FrameAllocator for Allocator {
         }
     }
 
-    fn deallocate(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn deallocate(&mut self, frame: Frame) {
+        self.buddy.free(frame.num_pages, frame.number);
     }
 }
@@ -1,12 +1,58 @@
-pub use self::paging::{PhysicalAddress, test_paging};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+pub use self::paging::{PhysicalAddress, VirtualAddress, CacheMode, Page, remove_identity_map};
 pub use self::alloc::Allocator;
+pub use self::working_set::{WorkingSet, Region, COLD_AGE};
+use self::paging::{ActivePageTable, EntryFlags, InactivePageTable, TemporaryPage};
+use block::BlockDevice;
+use multiboot2::BootInformation;
+use percpu;
+use smp;
+use sync::{Mutex, MutexGuard};
+use time;
 
 mod alloc;
 mod buddy;
 mod paging;
+mod swap;
+mod vmalloc;
+mod working_set;
 
 pub const PAGE_SIZE: usize = 4096;
 
+// The kernel is linked at this address (see linker.ld). boot.asm maps the first GiB of physical
+// memory here, so the kernel image, the VGA buffer and the multiboot information can be reached
+// by adding this offset to their physical address.
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+
+pub fn kernel_virt_to_phys(address: VirtualAddress) -> PhysicalAddress {
+    assert!(address >= KERNEL_OFFSET, "not a kernel address: 0x{:x}", address);
+    address - KERNEL_OFFSET
+}
+
+pub fn kernel_phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    assert!(address < 1 << 30, "outside of the kernel mapping: 0x{:x}", address);
+    address + KERNEL_OFFSET
+}
+
+// With the `direct-map` feature all of physical memory is mapped starting at this address
+// (P4 entry 256, the first entry of the higher half). boot.asm maps the first GiB, the rest is
+// mapped by `init_direct_map` once we know how much memory there is.
+#[cfg(feature = "direct-map")]
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff_8000_0000_0000;
+
+#[cfg(feature = "direct-map")]
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    address + PHYSICAL_MEMORY_OFFSET
+}
+
+// Reads a value straight out of physical memory through the direct map
+#[cfg(feature = "direct-map")]
+pub unsafe fn read_physical<T: Copy>(address: PhysicalAddress) -> T {
+    ::core::ptr::read_volatile(phys_to_virt(address) as *const T)
+}
+
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Frame {
     number: usize,
@@ -14,7 +60,7 @@ pub struct Frame {
 }
 
 impl Frame {
-    pub fn from_address(&self, address: usize, num_pages: usize) -> Frame {
+    pub fn from_address(address: usize, num_pages: usize) -> Frame {
         Frame{
             number: address / PAGE_SIZE,
             num_pages: num_pages,
@@ -24,9 +70,458 @@ impl Frame {
     pub fn start_address(&self) -> PhysicalAddress {
         self.number * PAGE_SIZE
     }
+
+    // Not `Clone` on purpose, copies of a frame are only made inside the memory module
+    // so a frame can't accidentally be freed twice.
+    fn clone(&self) -> Frame {
+        Frame{
+            number: self.number,
+            num_pages: self.num_pages,
+        }
+    }
 }
 
 pub trait FrameAllocator {
     fn allocate(&mut self, num_pages: usize) -> Option<Frame>;
     fn deallocate(&mut self, frame: Frame);
 }
+
+// Frames set aside for page tables before a mapping is made, see `MemoryController::map_to`
+struct FramePool {
+    frames: [Option<Frame>; 4],
+}
+
+impl FramePool {
+    fn new() -> FramePool {
+        FramePool{
+            frames: [None, None, None, None],
+        }
+    }
+
+    // Gives the frames that weren't needed back to `allocator`
+    fn release<A>(&mut self, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        for frame in self.frames.iter_mut().filter_map(|frame| frame.take()) {
+            allocator.deallocate(frame);
+        }
+    }
+}
+
+impl FrameAllocator for FramePool {
+    fn allocate(&mut self, _num_pages: usize) -> Option<Frame> {
+        self.frames.iter_mut().filter_map(|frame| frame.take()).next()
+    }
+
+    fn deallocate(&mut self, frame: Frame) {
+        let slot = self.frames.iter_mut().find(|slot| slot.is_none())
+            .expect("frame pool can hold only 4 frames");
+        *slot = Some(frame);
+    }
+}
+
+// Number of cold pages evicted at once when physical memory runs out
+const EVICT_BATCH: usize = 16;
+// Time between two working set scans
+const SCAN_INTERVAL: u64 = time::NSEC_PER_SEC / 10;
+
+/**
+Global so the page fault handler can swap pages back in. Not an `IrqLock`: vmalloc, eviction and
+swapping take long, and holding off interrupts for all of that would also hold off the TLB
+shootdowns other CPUs wait for. Interrupt handlers therefore never call `lock` on it:
+
+    handler              access
+    working set timer    `try_lock`, the scan is skipped if the controller is busy
+    page fault           `try_lock` in a loop, giving up if this CPU is the holder (see
+                         `CONTROLLER_OWNER`), so it never spins on a lock it interrupted
+**/
+static MEMORY_CONTROLLER: Mutex<Option<MemoryController>> = Mutex::new(None);
+// Number of the CPU holding `MEMORY_CONTROLLER` plus one, 0 while it's free. Tells a fault on
+// this CPU while it holds the controller apart from another CPU using it.
+static CONTROLLER_OWNER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Guard of `MEMORY_CONTROLLER` that keeps `CONTROLLER_OWNER` up to date
+struct ControllerGuard {
+    guard: MutexGuard<'static, Option<MemoryController>>,
+}
+
+impl ControllerGuard {
+    fn new(guard: MutexGuard<'static, Option<MemoryController>>) -> ControllerGuard {
+        CONTROLLER_OWNER.store(current_cpu() + 1, Ordering::SeqCst);
+        ControllerGuard{
+            guard: guard,
+        }
+    }
+
+    fn controller(&mut self) -> &mut MemoryController {
+        self.guard.as_mut().expect("memory::init has not been called")
+    }
+}
+
+impl Drop for ControllerGuard {
+    fn drop(&mut self) {
+        CONTROLLER_OWNER.store(0, Ordering::SeqCst);
+    }
+}
+
+// The memory controller is used before the per-CPU area exists, only by the BSP then
+fn current_cpu() -> usize {
+    if percpu::loaded() { percpu::cpu_id() } else { 0 }
+}
+
+// Runs `f` with the memory controller set up by `init`
+pub fn with_controller<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryController) -> R,
+{
+    f(ControllerGuard::new(MEMORY_CONTROLLER.lock()).controller())
+}
+
+// Like `with_controller`, but returns None instead of waiting if the controller is locked
+pub fn try_with_controller<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut MemoryController) -> R,
+{
+    MEMORY_CONTROLLER.try_lock().map(|guard| f(ControllerGuard::new(guard).controller()))
+}
+
+// Called by the page fault handler. Returns true if the fault was resolved (a swapped out page
+// was read back in) and the faulting instruction can be retried.
+pub fn handle_page_fault(address: VirtualAddress) -> bool {
+    loop {
+        if let Some(guard) = MEMORY_CONTROLLER.try_lock() {
+            let mut guard = ControllerGuard::new(guard);
+            return guard.guard.as_mut().map_or(false, |c| c.swap_in(address));
+        }
+        // The fault hit while this CPU holds the controller, e.g. in `vmalloc`. Those paths
+        // never touch swappable memory, so such a fault is a real bug and not ours to handle.
+        if CONTROLLER_OWNER.load(Ordering::SeqCst) == current_cpu() + 1 {
+            return false;
+        }
+        // Another CPU uses the controller and may wait for this one to flush its TLB, which
+        // the shootdown interrupt can't do while we handle the fault
+        smp::flush_pending_tlb();
+    }
+}
+
+// Samples the tracked regions every `SCAN_INTERVAL` from the timer interrupt, which ages the
+// pages `evict` picks from. Needs `time::init`.
+pub fn start_working_set_scanner() {
+    time::add_timer(SCAN_INTERVAL, scan_timer).expect("no timer for the working set scanner");
+}
+
+fn scan_timer() {
+    // Runs in interrupt context, a scan is skipped if the controller is busy
+    if let Some(guard) = MEMORY_CONTROLLER.try_lock() {
+        if let Some(controller) = ControllerGuard::new(guard).guard.as_mut() {
+            controller.scan_working_set();
+        }
+    }
+    time::add_timer(SCAN_INTERVAL, scan_timer);
+}
+
+// Writes a pattern to a swappable page, lets it go cold, swaps it out and faults it back in.
+// Needs a swap device and nothing else tracked, so `evict` can only pick this page.
+pub fn test_swap() {
+    const PATTERN: u64 = 0x5a5a_1234_dead_beef;
+
+    let address = with_controller(|memory_controller| {
+        let address = memory_controller.vmalloc_swappable(PAGE_SIZE)
+            .expect("no memory for the swap test");
+        unsafe { ptr::write_volatile(address as *mut u64, PATTERN) };
+        // The write made the page hot, it's cold once enough scans found it untouched
+        for _ in 0..COLD_AGE + 1 {
+            memory_controller.scan_working_set();
+        }
+        assert!(memory_controller.evict(1) == 1, "cold page wasn't swapped out");
+        assert!(memory_controller.active_table.translate(address).is_none(),
+                "swapped out page is still mapped");
+        address
+    });
+    // The controller is unlocked again, so the page fault handler can swap the page back in
+    let value = unsafe { ptr::read_volatile(address as *const u64) };
+    assert!(value == PATTERN, "swapped in page contains {:#x}", value);
+    with_controller(|memory_controller| memory_controller.vfree(address));
+    println!("swap test passed");
+}
+
+// Drops every translation this CPU has cached, for all PCIDs
+pub fn flush_tlb() {
+    use x86_64::instructions::tlb;
+
+    tlb::flush_all();
+    paging::flush_all_pcids();
+}
+
+// Drops stale translations after a mapping changed or went away: those of every PCID on this
+// CPU, since kernel mappings are cached under each of them, and the TLBs of the other CPUs,
+// which all run on the same page tables. The caller has invalidated the pages locally.
+fn flush_tlb_everywhere() {
+    paging::flush_all_pcids();
+    smp::tlb_shootdown();
+}
+
+// Per CPU paging setup of an application processor
+pub fn init_ap() {
+    paging::init_pat_ap();
+    paging::init_pcid_ap();
+}
+
+pub fn init(boot_info: &BootInformation) {
+    paging::init_pat();
+    paging::init_pcid();
+
+	let memory_map_tag = boot_info.memory_map_tag()
+		.expect("Memory map tag required");
+
+	println!("memory areas:");
+	for area in memory_map_tag.memory_areas() {
+		println!("    start: 0x{:x}, length: 0x{:x}",
+			area.base_addr, area.length);
+	}
+
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("Elf-sections tag required");
+
+	//println!("kernel sections:");
+	//for section in elf_sections_tag.sections() {
+	//	println!("    addr: 0x{:x}, size: 0x{:x}, flags: 0x{:x}",
+	//		section.addr, section.size, section.flags);
+	//}
+
+	let kernel_start = elf_sections_tag.sections().map(|s| s.addr)
+		.min().unwrap();
+    // The kernel is linked in the higher half, the allocator works with physical addresses.
+    // Sections outside the image, like the symbol table `backtrace` uses, are loaded by GRUB
+    // and have physical addresses.
+    let kernel_end = elf_sections_tag.sections().map(|s| {
+        let end = (s.addr + s.size) as usize;
+        if s.addr as usize >= KERNEL_OFFSET { kernel_virt_to_phys(end) } else { end }
+    }).max().unwrap();
+
+	let multiboot_start = kernel_virt_to_phys(boot_info as *const _ as usize);
+	let multiboot_end = multiboot_start + (boot_info.total_size as usize);
+
+    println!("kernel start: 0x{:x}, kernel end: 0x{:x}", kernel_start, kernel_end);
+    println!("multiboot start: 0x{:x}, multiboot end: 0x{:x}", multiboot_start, multiboot_end);
+    let mut allocator = Allocator::new(kernel_end, multiboot_start, multiboot_end);
+    #[cfg(feature = "direct-map")]
+    paging::init_direct_map(memory_map_tag, &mut allocator);
+    let mut active_table = unsafe { ActivePageTable::new() };
+    vmalloc::init(&mut active_table, &mut allocator);
+
+    *MEMORY_CONTROLLER.lock() = Some(MemoryController{
+        active_table: active_table,
+        allocator: allocator,
+        working_set: WorkingSet::new(),
+    });
+}
+
+// Owns the active page table and the frame allocator so the rest of the kernel doesn't have to
+// thread both through every call.
+pub struct MemoryController {
+    active_table: ActivePageTable,
+    allocator: Allocator,
+    working_set: WorkingSet,
+}
+
+impl MemoryController {
+    // Maps `size` bytes of virtually contiguous memory backed by single frames. Cold pages are
+    // swapped out if physical memory runs out.
+    pub fn vmalloc(&mut self, size: usize) -> Option<VirtualAddress> {
+        vmalloc::vmalloc(size, self)
+    }
+
+    // Like `vmalloc`, but the pages are anonymous memory the working set scanner samples and
+    // cold ones may be swapped out. Swapping them back in needs the controller, so the memory
+    // must not be touched while it's locked.
+    pub fn vmalloc_swappable(&mut self, size: usize) -> Option<VirtualAddress> {
+        let address = match self.vmalloc(size) {
+            Some(address) => address,
+            None => return None,
+        };
+        if !self.working_set.add_region(address, (size + PAGE_SIZE - 1) / PAGE_SIZE) {
+            self.vfree(address);
+            return None;
+        }
+        Some(address)
+    }
+
+    // Allocates a single frame, swapping out cold pages if physical memory runs out
+    pub fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocator.allocate(1).or_else(|| {
+            if self.evict(EVICT_BATCH) == 0 {
+                return None;
+            }
+            self.allocator.allocate(1)
+        })
+    }
+
+    // `Mapper::map_to` with the page tables it needs allocated up front by `allocate_frame`, so
+    // they can evict cold pages as well. Hands `frame` back if there is no memory for them.
+    fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> Result<(), Frame> {
+        let mut tables = FramePool::new();
+        for _ in 0..self.active_table.tables_needed(page) {
+            match self.allocate_frame() {
+                Some(table) => tables.deallocate(table),
+                None => {
+                    tables.release(&mut self.allocator);
+                    return Err(frame);
+                }
+            }
+        }
+        self.active_table.map_to(page, frame, flags, &mut tables);
+        tables.release(&mut self.allocator);
+        Ok(())
+    }
+
+    pub fn vfree(&mut self, address: VirtualAddress) {
+        self.working_set.remove_region(address);
+        vmalloc::vfree(address, &mut self.active_table, &mut self.allocator)
+    }
+
+    // Maps the physical range `[address, address + size)` (e.g. device registers) uncached
+    pub fn ioremap(&mut self, address: PhysicalAddress, size: usize) -> Option<VirtualAddress> {
+        self.ioremap_cache(address, size, CacheMode::Uncacheable)
+    }
+
+    // Like `ioremap` with an explicit memory type, e.g. `WriteCombining` for a framebuffer
+    pub fn ioremap_cache(&mut self, address: PhysicalAddress, size: usize, mode: CacheMode)
+        -> Option<VirtualAddress>
+    {
+        vmalloc::ioremap(address, size, mode, self)
+    }
+
+    pub fn iounmap(&mut self, address: VirtualAddress) {
+        vmalloc::iounmap(address, &mut self.active_table)
+    }
+
+    // Identity maps the page at `address` for code that runs while paging is being enabled,
+    // like the AP trampoline. The lower half is empty otherwise since `remove_identity_map`.
+    // Returns false if there is no memory for the page tables.
+    pub fn identity_map(&mut self, address: PhysicalAddress) -> bool {
+        let frame = Frame::from_address(address, 1);
+        self.map_to(Page::from_address(address), frame, EntryFlags::WRITABLE).is_ok()
+    }
+
+    // Removes a mapping created by `identity_map` and the page tables that only existed for it,
+    // the frame isn't freed
+    pub fn unmap_identity(&mut self, address: PhysicalAddress) {
+        let page = Page::from_address(address);
+        self.active_table.unmap_frame(page);
+        flush_tlb_everywhere();
+        self.active_table.free_empty_tables(page, &mut self.allocator);
+    }
+
+    // Maps `pages` zeroed pages starting at `address` in the user half, accessible from ring 3.
+    // They are anonymous memory, so the working set scanner samples them.
+    pub fn map_user(&mut self, address: VirtualAddress, pages: usize, writable: bool) -> bool {
+        let flags = if writable { EntryFlags::WRITABLE } else { EntryFlags::empty() };
+        assert!(address % PAGE_SIZE == 0, "user mapping is not page aligned");
+        for i in 0..pages {
+            let page = Page::from_address(address + i * PAGE_SIZE);
+            let frame = match self.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            if let Err(frame) = self.map_to(page, frame, flags | EntryFlags::USER_ACCESSIBLE) {
+                self.allocator.deallocate(frame);
+                return false;
+            }
+            unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE) };
+        }
+        // With the region table full the pages just never get evicted
+        self.working_set.add_region(address, pages);
+        true
+    }
+
+    // Checks that every page of `[address, address + length)` is mapped for ring 3, which
+    // includes pages that are swapped out. `address` has to be in the user half.
+    pub fn is_user_accessible(&self, address: VirtualAddress, length: usize) -> bool {
+        if length == 0 {
+            return true;
+        }
+        let first = address / PAGE_SIZE;
+        let last = (address + length - 1) / PAGE_SIZE;
+        (first..last + 1).all(|number| {
+            let entry = match self.active_table.entry(Page::from_address(number * PAGE_SIZE)) {
+                Some(entry) => entry,
+                None => return false,
+            };
+            // Touching a swapped out page reads it back in
+            if entry.swap_slot().is_some() {
+                entry.swapped_flags().contains(EntryFlags::USER_ACCESSIBLE)
+            } else {
+                entry.flags().contains(EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE)
+            }
+        })
+    }
+
+    // Unmaps everything in the user half and frees its frames and page tables
+    pub fn free_user_memory(&mut self) {
+        use x86_64::instructions::tlb;
+
+        self.working_set.remove_user_regions();
+        self.active_table.free_user_half(&mut self.allocator);
+        tlb::flush_all();
+        smp::tlb_shootdown();
+    }
+
+    // Includes `[start, start + pages * PAGE_SIZE)` in working set scans
+    pub fn track_region(&mut self, start: VirtualAddress, pages: usize) -> bool {
+        self.working_set.add_region(start, pages)
+    }
+
+    pub fn untrack_region(&mut self, start: VirtualAddress) {
+        self.working_set.remove_region(start)
+    }
+
+    // Samples the ACCESSED/DIRTY bits of all tracked regions, should run periodically
+    pub fn scan_working_set(&mut self) {
+        self.working_set.scan(&mut self.active_table)
+    }
+
+    pub fn working_set_region(&self, start: VirtualAddress) -> Option<Region> {
+        self.working_set.region(start)
+    }
+
+    // See `WorkingSet::cold_pages`
+    pub fn cold_pages(&self, min_age: u8, out: &mut [Page]) -> usize {
+        self.working_set.cold_pages(&self.active_table, min_age, out)
+    }
+
+    // Swaps to `device` from now on. Only pages of tracked regions (anonymous memory) are ever
+    // evicted.
+    pub fn add_swap(&mut self, device: &'static Mutex<BlockDevice + Send>) {
+        swap::set_device(device)
+    }
+
+    // Writes up to `count` of the coldest tracked pages to swap and frees their frames.
+    // Returns the number of evicted pages.
+    pub fn evict(&mut self, count: usize) -> usize {
+        let mut pages = [Page::from_address(0); EVICT_BATCH];
+        let count = ::core::cmp::min(count, EVICT_BATCH);
+        let found = self.working_set.cold_pages(&self.active_table, COLD_AGE, &mut pages[..count]);
+        pages[..found].iter()
+            .filter(|&&page| swap::swap_out(page, &mut self.active_table, &mut self.allocator))
+            .count()
+    }
+
+    fn swap_in(&mut self, address: VirtualAddress) -> bool {
+        let page = Page::from_address(address);
+        swap::swap_in(page, &mut self.active_table, &mut self.allocator)
+    }
+
+    // Frees the user half, P4 frame and PCID of `table`, see `InactivePageTable::teardown`
+    pub fn free_page_table(&mut self, table: &mut InactivePageTable) {
+        let page = Page::from_address(vmalloc::TEMPORARY_PAGE);
+        let mut temporary_page = TemporaryPage::new(page, &mut self.allocator);
+        table.free(&mut self.active_table, &mut temporary_page, &mut self.allocator);
+        temporary_page.free(&mut self.allocator);
+    }
+
+    pub fn test_paging(&mut self) {
+        paging::test_paging(&mut self.allocator)
+    }
+}
@@ -1,7 +1,12 @@
-use memory::Frame;
-use memory::paging::ENTRY_COUNT;
+use memory::{Frame, PAGE_SIZE};
 
 const FLAG_MASK: usize = 0x000FFFFF_FFFFF000;
+// Address bits of huge P2/P3 entries, bit 12 is their PAT bit
+const HUGE_ADDRESS_MASK: usize = 0x000FFFFF_FFFFE000;
+// Bits 52-55 count how many working set scans in a row found the page unaccessed
+const AGE_SHIFT: u64 = 52;
+const AGE_MASK: u64 = 0xf << AGE_SHIFT;
+pub const MAX_AGE: u8 = 0xf;
 
 /**
 The bit layout of each page table entry is as follows:
@@ -13,11 +18,15 @@ The bit layout of each page table entry is as follows:
     4       disable cache               no cache is used for this page
     5       accessed                    the CPU sets this bit when this page is used
     6       dirty                       the CPU sets this bit when a write to this page occurs
-    7       huge page/null              must be 0 in P1 and P4, creates a 1GiB page in P3, creates a 2MiB page in P2
+    7       huge page/PAT               must be 0 in P4, creates a 1GiB page in P3, creates a 2MiB page in P2,
+                                        selects the PAT entry (with bits 3 and 4) in P1
     8       global                      page isn't flushed from caches on address space switch (PGE bit of CR4 register must be set)
-    9-11    available                   can be used freely by the OS
+    9-11    available                   can be used freely by the OS (bit 9: frame is shared, see `SHARED`,
+                                        bit 10: page is swapped out, see `SWAPPED`,
+                                        bit 11: dirty bit saved by the working set scanner, see `SOFT_DIRTY`)
     12-51   physical address            the page aligned 52bit physical address of the frame or the   next page table
-    52-62   available                   can be used freely by the OS
+                                        (bit 12 is the PAT bit of huge pages, which are at least 2MiB aligned)
+    52-62   available                   can be used freely by the OS (bits 52-55: page age, see `age`)
     63      no execute                  forbid executing code on this page (the NXE bit in the EFER register must be set)
 **/
 pub struct Entry(u64);
@@ -31,23 +40,88 @@ impl Entry {
         self.0 = 0;
     }
 
+    // The address bits are masked out: `HUGE_PAT` shares bit 12 with the frame address of P1
+    // entries and would show up for every odd frame. Flags can thus be copied into `set` safely.
     pub fn flags(&self) -> EntryFlags {
-        EntryFlags::from_bits_truncate(self.0)
+        EntryFlags::from_bits_truncate(self.0 & !(FLAG_MASK as u64))
     }
 
+    // Frame of a P1 entry or next table of a higher level entry. Huge entries have to use
+    // `huge_frame_pointer`, their PAT bit would count as part of the address here.
     pub fn frame_pointer(&self) -> Option<Frame> {
         if self.flags().contains(EntryFlags::PRESENT) {
             Some(Frame{
                 num_pages: 1,
                 // Bits 12-51 represent the physical address
                 // of the frame or next page table
-                number: self.0 as usize & FLAG_MASK,
+                number: (self.0 as usize & FLAG_MASK) / PAGE_SIZE,
             })
         } else {
             None
         }
     }
 
+    // Flags of a huge P2/P3 entry, unlike `flags` including `HUGE_PAT`
+    pub fn huge_flags(&self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0 & !(HUGE_ADDRESS_MASK as u64))
+    }
+
+    // First frame of a huge P2/P3 entry
+    pub fn huge_frame_pointer(&self) -> Option<Frame> {
+        if self.flags().contains(EntryFlags::PRESENT) {
+            Some(Frame{
+                num_pages: 1,
+                number: (self.0 as usize & HUGE_ADDRESS_MASK) / PAGE_SIZE,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Clears flag bits without touching the frame, e.g. ACCESSED/DIRTY after sampling them
+    pub fn remove_flags(&mut self, flags: EntryFlags) {
+        self.0 &= !flags.bits();
+    }
+
+    pub fn insert_flags(&mut self, flags: EntryFlags) {
+        self.0 |= flags.bits();
+    }
+
+    // Number of consecutive working set scans that found this page unaccessed
+    pub fn age(&self) -> u8 {
+        ((self.0 & AGE_MASK) >> AGE_SHIFT) as u8
+    }
+
+    pub fn set_age(&mut self, age: u8) {
+        let age = if age > MAX_AGE { MAX_AGE } else { age };
+        self.0 = (self.0 & !AGE_MASK) | ((age as u64) << AGE_SHIFT);
+    }
+
+    // Swap slot of a swapped out page, see `set_swapped`
+    pub fn swap_slot(&self) -> Option<usize> {
+        let flags = self.flags();
+        if !flags.contains(EntryFlags::PRESENT) && flags.contains(EntryFlags::SWAPPED) {
+            Some((self.0 as usize & FLAG_MASK) / PAGE_SIZE)
+        } else {
+            None
+        }
+    }
+
+    // Turns the entry into a non-present swap entry. The slot number takes the place of the
+    // frame number and the flags are kept for when the page is read back in.
+    pub fn set_swapped(&mut self, slot: usize, flags: EntryFlags) {
+        let slot_bits = slot * PAGE_SIZE;
+        assert!(slot_bits & !FLAG_MASK == 0, "swap slot {} too large", slot);
+        let flags = flags - EntryFlags::PRESENT - EntryFlags::ACCESSED - EntryFlags::DIRTY
+            - EntryFlags::HUGE_PAT;
+        self.0 = (slot_bits as u64) | (flags | EntryFlags::SWAPPED).bits();
+    }
+
+    // Flags a swapped out page had before `set_swapped`
+    pub fn swapped_flags(&self) -> EntryFlags {
+        self.flags() - EntryFlags::SWAPPED
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         // Assert that the frame address has no flag bits set.
         // Flag bits are bits > 51 and < 12 since the physical address only sits in bits 12-51.
@@ -71,7 +145,21 @@ bitflags! {
         const ACCESSED =        1 << 5;
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
+        // Same bit as `HUGE_PAGE`, it only means PAT in P1 entries
+        const PAT =             1 << 7;
         const GLOBAL =          1 << 8;
+        // PAT bit of huge P2/P3 entries. Part of the frame address in P1 entries, so only
+        // `huge_flags` reports it.
+        const HUGE_PAT =        1 << 12;
+        // OS bit, the frame is owned by someone else (MMIO, shared memory) and must not be
+        // freed when the mapping goes away
+        const SHARED =          1 << 9;
+        // OS bit, only meaningful while PRESENT is clear: the page was swapped out and bits
+        // 12-51 hold its swap slot instead of a frame address
+        const SWAPPED =         1 << 10;
+        // OS bit, DIRTY was set at some point since the page was mapped. The working set
+        // scanner clears DIRTY and remembers it here.
+        const SOFT_DIRTY =      1 << 11;
         const NO_EXECUTE =      1 << 63;
     }
 }
@@ -1,17 +1,56 @@
 use core::ptr::Unique;
 
-pub use self::entry::EntryFlags;
-use self::table::{Table, Level4, P4};
+use memory::paging::{CacheMode, Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT, paging_levels};
+use memory::paging::entry::{Entry, EntryFlags};
+use memory::paging::table::{self, Table, Level5, Level4, Level3, Level2};
 use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::swap;
+
+// Size of a page mapped directly by a P2 entry
+pub const HUGE_PAGE_SIZE: usize = 512 * PAGE_SIZE;
+
+// The table CR3 points at, which depends on the paging mode boot.asm picked
+enum TopTable {
+    Level4(Unique<Table<Level4>>),
+    Level5(Unique<Table<Level5>>),
+}
 
 pub struct Mapper {
-    p4: Unique<Table<Level4>>,
+    top: TopTable,
 }
 
 impl Mapper {
+    #[cfg(not(feature = "direct-map"))]
+    pub unsafe fn new() -> Mapper {
+        let top = if paging_levels() == 5 {
+            TopTable::Level5(Unique::new_unchecked(table::P5))
+        } else {
+            TopTable::Level4(Unique::new_unchecked(table::P4))
+        };
+        Mapper{
+            top: top,
+        }
+    }
+
+    #[cfg(feature = "direct-map")]
     pub unsafe fn new() -> Mapper {
+        use x86_64::registers::control_regs;
+
+        let top_frame = Frame::from_address(control_regs::cr3().0 as usize, 1);
+        Mapper::from_table_frame(&top_frame)
+    }
+
+    // Creates a mapper for any top level table, active or not. Only possible through the direct
+    // map since the recursive mapping always points at the active table.
+    #[cfg(feature = "direct-map")]
+    pub unsafe fn from_table_frame(frame: &Frame) -> Mapper {
+        let top = if paging_levels() == 5 {
+            TopTable::Level5(Unique::new_unchecked(table::table_from_frame(frame)))
+        } else {
+            TopTable::Level4(Unique::new_unchecked(table::table_from_frame(frame)))
+        };
         Mapper{
-            p4: Unique::new_unchecked(table::P4),
+            top: top,
         }
     }
 
@@ -42,51 +81,245 @@ impl Mapper {
     where
         A: FrameAllocator,
     {
-        let p3 = self.p4_mut().next_table_or_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_or_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_or_create(page.p2_index(), allocator);
+        // User pages need USER_ACCESSIBLE on the tables above them too
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
+        let p4 = self.p4_or_create(page, table_flags, allocator);
+        let p3 = p4.next_table_or_create(page.p4_index(), table_flags, allocator);
+        let p2 = p3.next_table_or_create(page.p3_index(), table_flags, allocator);
+        let p1 = p2.next_table_or_create(page.p2_index(), table_flags, allocator);
 
         // Make sure that the p1 table entry is unused
         assert!(p1[page.p1_index()].is_unused());
-        println!("after is unused");
         // Flip the PRESENT flag and map the p1 table entry to the physical frame
-        println!("mapping p1 index {} to frame {:?}", page.p1_index(), frame);
         p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
     }
 
+    // Maps a 2MiB huge page by setting the P2 entry directly, with the memory type `mode`. Both
+    // the page and the frame have to be 2MiB aligned.
+    pub fn map_to_2mib<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags,
+                          mode: CacheMode, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        assert!(page.start_address() % HUGE_PAGE_SIZE == 0, "huge page is not 2MiB aligned");
+        assert!(frame.start_address() % HUGE_PAGE_SIZE == 0, "huge frame is not 2MiB aligned");
+        let table_flags = flags & EntryFlags::USER_ACCESSIBLE;
+        let p4 = self.p4_or_create(page, table_flags, allocator);
+        let p3 = p4.next_table_or_create(page.p4_index(), table_flags, allocator);
+        let p2 = p3.next_table_or_create(page.p3_index(), table_flags, allocator);
+
+        assert!(p2[page.p2_index()].is_unused());
+        p2[page.p2_index()].set(frame, flags | mode.huge_flags() | EntryFlags::PRESENT
+                                       | EntryFlags::HUGE_PAGE);
+    }
+
+    // Unmaps the page and returns its frame to the allocator. A swapped out page only gives
+    // back its swap slot.
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
     where
         A: FrameAllocator,
     {
-        let p1 = self.p4_mut().next_table_mut(page.p4_index())
+        if let Some(entry) = self.entry_mut(page) {
+            if let Some(slot) = entry.swap_slot() {
+                swap::free_slot(slot);
+                entry.set_unused();
+                return;
+            }
+        }
+        let frame = self.unmap_frame(page);
+        allocator.deallocate(frame);
+    }
+
+    // Unmaps the page and hands back the frame it pointed to without freeing it. Used for
+    // frames the mapping doesn't own, e.g. MMIO or the target of a temporary page.
+    pub fn unmap_frame(&mut self, page: Page) -> Frame {
+        let p1 = self.p4_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index()))
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
             // Only error expected at this point
             .expect("huge pages disabled");
-        // Free frame pointer
-        // allocator.free(p1[page.p1_index()].frame_pointer().expect("tried to unmap an unused
-        // page"))
-        p1[page.p2_index()].set_unused();
+        let frame = p1[page.p1_index()].frame_pointer().expect("tried to unmap an unused page");
+        p1[page.p1_index()].set_unused();
 
 		// Flush the tlb cache
 		use x86_64::instructions::tlb;
 		use x86_64::VirtualAddress;
 		tlb::flush(VirtualAddress(page.start_address()));
+
+        frame
     }
 
-    fn p4(&self) -> &Table<Level4> {
-        unsafe { self.p4.as_ref() }
+    // Frees the tables on the way to `page` that no longer map anything, bottom up. Only for
+    // the user half, kernel tables are shared through the top level entries every address
+    // space copies.
+    pub fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        assert!(!page.is_kernel(), "kernel page tables are shared");
+        let p4_empty = {
+            let p4 = match self.p4_mut(page) {
+                Some(p4) => p4,
+                None => return,
+            };
+            let p3_empty = match p4.next_table_mut(page.p4_index()) {
+                Some(p3) => {
+                    let p2_empty = match p3.next_table_mut(page.p3_index()) {
+                        Some(p2) => {
+                            let p1_empty = p2.next_table(page.p2_index())
+                                .map_or(false, |p1| p1.is_empty());
+                            if p1_empty {
+                                free_table_frame(&mut p2[page.p2_index()], allocator);
+                            }
+                            p2.is_empty()
+                        }
+                        None => false,
+                    };
+                    if p2_empty {
+                        free_table_frame(&mut p3[page.p3_index()], allocator);
+                    }
+                    p3.is_empty()
+                }
+                None => false,
+            };
+            if p3_empty {
+                free_table_frame(&mut p4[page.p4_index()], allocator);
+            }
+            p4.is_empty()
+        };
+        // With 5-level paging the P4 table hangs off a P5 entry as well
+        if let TopTable::Level5(ref mut p5) = self.top {
+            if p4_empty {
+                free_table_frame(unsafe { &mut p5.as_mut()[page.p5_index()] }, allocator);
+            }
+        }
     }
 
-    fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { self.p4.as_mut() }
+    // Number of page tables `map_to` has to create to map `page`
+    pub fn tables_needed(&self, page: Page) -> usize {
+        let p4 = match self.p4(page) {
+            Some(p4) => p4,
+            None => return 4,
+        };
+        let p3 = match p4.next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return 3,
+        };
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return 2,
+        };
+        if p2.next_table(page.p2_index()).is_some() { 0 } else { 1 }
     }
 
-    fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
+    // P1 entry that maps `page`, if all the tables above it exist
+    pub fn entry(&self, page: Page) -> Option<&Entry> {
+        self.p4(page)
+            .and_then(|p4| p4.next_table(page.p4_index()))
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map(|p1| &p1[page.p1_index()])
+    }
+
+    pub fn entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.p4_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index()))
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .map(|p1| &mut p1[page.p1_index()])
+    }
+
+    // Entry of the top level table (P4, or P5 with 5-level paging)
+    pub fn top_table_entry(&self, index: usize) -> &Entry {
+        match self.top {
+            TopTable::Level4(ref p4) => unsafe { &p4.as_ref()[index] },
+            TopTable::Level5(ref p5) => unsafe { &p5.as_ref()[index] },
+        }
+    }
+
+    pub fn top_table_entry_mut(&mut self, index: usize) -> &mut Entry {
+        match self.top {
+            TopTable::Level4(ref mut p4) => unsafe { &mut p4.as_mut()[index] },
+            TopTable::Level5(ref mut p5) => unsafe { &mut p5.as_mut()[index] },
+        }
+    }
+
+    // Makes sure the top level entry covering `address` points at a table. New address spaces
+    // copy the kernel's top level entries, so mappings added below an entry that existed back
+    // then show up in all of them.
+    pub fn create_top_table_entry<A>(&mut self, address: VirtualAddress, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let page = Page::from_address(address);
+        match self.top {
+            TopTable::Level4(ref mut p4) => {
+                unsafe { p4.as_mut() }.next_table_or_create(page.p4_index(), EntryFlags::empty(),
+                                                            allocator);
+            }
+            TopTable::Level5(ref mut p5) => {
+                unsafe { p5.as_mut() }.next_table_or_create(page.p5_index(), EntryFlags::empty(),
+                                                            allocator);
+            }
+        }
+    }
+
+    // The P4 table responsible for `page`. With 4-level paging there is only one.
+    fn p4(&self, page: Page) -> Option<&Table<Level4>> {
+        match self.top {
+            TopTable::Level4(ref p4) => Some(unsafe { p4.as_ref() }),
+            TopTable::Level5(ref p5) => unsafe { p5.as_ref() }.next_table(page.p5_index()),
+        }
+    }
+
+    fn p4_mut(&mut self, page: Page) -> Option<&mut Table<Level4>> {
+        match self.top {
+            TopTable::Level4(ref mut p4) => Some(unsafe { p4.as_mut() }),
+            TopTable::Level5(ref mut p5) => unsafe { p5.as_mut() }.next_table_mut(page.p5_index()),
+        }
+    }
+
+    fn p4_or_create<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+                       -> &mut Table<Level4>
+    where
+        A: FrameAllocator,
+    {
+        match self.top {
+            TopTable::Level4(ref mut p4) => unsafe { p4.as_mut() },
+            TopTable::Level5(ref mut p5) => unsafe { p5.as_mut() }
+                .next_table_or_create(page.p5_index(), flags, allocator),
+        }
+    }
+
+    pub fn translate_page(&self, page: Page) -> Option<Frame> {
+        let p3 = self.p4(page).and_then(|p4| p4.next_table(page.p4_index()));
 
         let huge_page = || {
-            None
+            let p3 = match p3 {
+                Some(p3) => p3,
+                None => return None,
+            };
+            let p3_entry = &p3[page.p3_index()];
+            if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+                // 1GiB page
+                return p3_entry.huge_frame_pointer().map(|frame| Frame{
+                    number: frame.number + page.p2_index() * ENTRY_COUNT + page.p1_index(),
+                    num_pages: 1,
+                });
+            }
+            p3.next_table(page.p3_index()).and_then(|p2| {
+                let p2_entry = &p2[page.p2_index()];
+                if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+                    // 2MiB page
+                    p2_entry.huge_frame_pointer().map(|frame| Frame{
+                        number: frame.number + page.p1_index(),
+                        num_pages: 1,
+                    })
+                } else {
+                    None
+                }
+            })
         };
 
         p3.and_then(|p3| p3.next_table(page.p3_index()))
@@ -94,4 +327,97 @@ impl Mapper {
             .and_then(|p1| p1[page.p1_index()].frame_pointer())
             .or_else(huge_page)
     }
+
+    // Frees every page table and every owned frame mapped in the lower (user) half. Entries
+    // marked `SHARED` point at frames owned by someone else and are only unmapped.
+    pub fn free_user_half<A>(&mut self, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        for index in 0..ENTRY_COUNT / 2 {
+            match self.top {
+                TopTable::Level4(ref mut p4) => free_p3(unsafe { p4.as_mut() }, index, allocator),
+                TopTable::Level5(ref mut p5) => free_p4(unsafe { p5.as_mut() }, index, allocator),
+            }
+        }
+    }
+}
+
+// The `free_pN` functions free the PN table referenced by `table[index]`, everything mapped
+// below it and then clear the entry.
+
+fn free_p4<A>(p5: &mut Table<Level5>, index: usize, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    if let Some(p4) = p5.next_table_mut(index) {
+        for i in 0..ENTRY_COUNT {
+            free_p3(p4, i, allocator);
+        }
+    }
+    free_table_frame(&mut p5[index], allocator);
+}
+
+fn free_p3<A>(p4: &mut Table<Level4>, index: usize, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    if let Some(p3) = p4.next_table_mut(index) {
+        for i in 0..ENTRY_COUNT {
+            free_p2(p3, i, allocator);
+        }
+    }
+    free_table_frame(&mut p4[index], allocator);
+}
+
+fn free_p2<A>(p3: &mut Table<Level3>, index: usize, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    if let Some(p2) = p3.next_table_mut(index) {
+        for i in 0..ENTRY_COUNT {
+            free_p1(p2, i, allocator);
+        }
+    }
+    free_table_frame(&mut p3[index], allocator);
+}
+
+fn free_p1<A>(p2: &mut Table<Level2>, index: usize, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    if let Some(p1) = p2.next_table_mut(index) {
+        for i in 0..ENTRY_COUNT {
+            free_data_frame(&mut p1[i], allocator);
+        }
+    }
+    free_table_frame(&mut p2[index], allocator);
+}
+
+// Frees the page table an entry points to. Huge pages are never allocated by the mapper, so
+// their frames aren't ours to free.
+fn free_table_frame<A>(entry: &mut Entry, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    if !entry.flags().contains(EntryFlags::HUGE_PAGE) {
+        if let Some(frame) = entry.frame_pointer() {
+            allocator.deallocate(frame);
+        }
+    }
+    entry.set_unused();
+}
+
+fn free_data_frame<A>(entry: &mut Entry, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    if let Some(slot) = entry.swap_slot() {
+        swap::free_slot(slot);
+    } else if !entry.flags().contains(EntryFlags::SHARED) {
+        if let Some(frame) = entry.frame_pointer() {
+            allocator.deallocate(frame);
+        }
+    }
+    entry.set_unused();
 }
@@ -1,18 +1,61 @@
-use core::ptr::Unique;
+use core::mem;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
-pub use self::entry::EntryFlags;
-use self::table::{Table, Level4, P4};
-use self::temporary_page::{TemporaryPage};
-use self::mapper::{Mapper};
+pub use self::entry::{EntryFlags, MAX_AGE};
+pub use self::mapper::{Mapper, HUGE_PAGE_SIZE};
+pub use self::pat::{CacheMode, init as init_pat, init_ap as init_pat_ap};
+pub use self::pcid::{init as init_pcid, init_ap as init_pcid_ap, flush_all_pcids};
+pub use self::temporary_page::TemporaryPage;
+use self::table::{Table, TableLevel};
 use memory::{PAGE_SIZE, Frame, FrameAllocator};
+#[cfg(feature = "direct-map")]
+use self::table::Level4;
+#[cfg(feature = "direct-map")]
+use memory::phys_to_virt;
+#[cfg(feature = "direct-map")]
+use multiboot2::MemoryMapTag;
 
 mod table;
 mod entry;
 mod temporary_page;
 mod mapper;
+mod pat;
+mod pcid;
 
 const ENTRY_COUNT: usize = 512;
+// Entry of the top level table (P4, or P5 with 5-level paging) that points back at the table
+// itself. Entry 511 holds the kernel's higher half mapping, so the recursive mapping uses the
+// one below it.
+const RECURSIVE_INDEX: usize = 510;
+
+// Number of paging levels, 4 or 5. Zero until the first call to `paging_levels`.
+static PAGING_LEVELS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// boot.asm turns on 5-level paging (CR4.LA57) when CPUID reports it, we just read back its choice
+pub fn paging_levels() -> usize {
+    let levels = PAGING_LEVELS.load(Ordering::Relaxed);
+    if levels != 0 {
+        return levels;
+    }
+    use x86_64::registers::control_regs;
+    let levels = if control_regs::cr4().bits() as usize & CR4_LA57 != 0 { 5 } else { 4 };
+    PAGING_LEVELS.store(levels, Ordering::Relaxed);
+    levels
+}
+
+const CR4_LA57: usize = 1 << 12;
+
+// Number of significant bits in a virtual address, the rest are sign extension
+fn virtual_address_bits() -> usize {
+    if paging_levels() == 5 { 57 } else { 48 }
+}
+
+// Copies the most significant address bit into the sign extension bits
+fn sign_extend(address: usize) -> VirtualAddress {
+    let shift = 64 - virtual_address_bits();
+    (((address << shift) as isize) >> shift) as usize
+}
 
 // Each physical address should be page aligned to not have any 0-11 bits set.
 // x86 physical addresses should be smaller than 2^52. This means that physical addresses
@@ -22,6 +65,7 @@ pub type PhysicalAddress = usize;
 // Bits 0-11 are offset bits while bits 12-47 are page table index bits.
 // x86 virtual addresses should be smaller than 2^48. Only bits 0-47 are set w/ the remaining
 // bits 48-63 are sign extension bits (copies of the MSB).
+// With 5-level paging bits 48-56 are the P5 index and only bits 57-63 are sign extension.
 pub type VirtualAddress = usize;
 
 /**
@@ -32,14 +76,18 @@ The bit layout of a virtual address is as follows:
     21-29   P2 index            Entry index on the P2 page table
     30-38   P3 index            Entry index on the P3 page table
     39-47   P4 index            Entry index on the P4 page table
+    48-56   P5 index            Entry index on the P5 page table (5-level paging only)
 
-Our P4 table is recursively mapped so table access adheres to the following invariant:
+Our P4 table is recursively mapped (entry 510) so table access adheres to the following invariant:
     Table   Address                             Indexes
-    P4      0o177777_777_777_777_777_0000       –
-    P3      0o177777_777_777_777_XXX_0000       XXX is the P4 index
-    P2      0o177777_777_777_XXX_YYY_0000       like above, and YYY is the P3 index
-    P1      0o177777_777_XXX_YYY_ZZZ_0000       like above, and ZZZ is the P2 index
+    P4      0o177777_776_776_776_776_0000       –
+    P3      0o177777_776_776_776_XXX_0000       XXX is the P4 index
+    P2      0o177777_776_776_XXX_YYY_0000       like above, and YYY is the P3 index
+    P1      0o177777_776_XXX_YYY_ZZZ_0000       like above, and ZZZ is the P2 index
 Where bits 0o177777 (48-63) are the sign extension bits.
+
+With 5-level paging the P5 table is the recursively mapped one, it lives at
+0o177_776_776_776_776_776_0000 and every table above moves one level further down.
 **/
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
@@ -48,12 +96,10 @@ pub struct Page {
 
 impl Page {
     pub fn from_address(address: VirtualAddress) -> Page {
-        // Addresses on x86 are just 48 bits long, the remaining bits are just sign extension
-        // (copies of the msb). Address space is split in two halves. A higher half w/ sign
-        // extension and a lower half w/o.
-		assert!(address < 0x0000_8000_0000_0000 ||
-			address >= 0xffff_8000_0000_0000,
-			"invalid address: 0x{:x}", address);
+        // Addresses on x86 are just 48 (or 57 w/ 5-level paging) bits long, the remaining bits
+        // are just sign extension (copies of the msb). Address space is split in two halves.
+        // A higher half w/ sign extension and a lower half w/o.
+		assert!(sign_extend(address) == address, "invalid address: 0x{:x}", address);
         // NOTE: When we divide by `PAGE_SIZE`, the index methods below do not require a 12 bit
         // shift since this division basically shifts the bits over by 12 bits when page size is
         // 4096. See below:
@@ -65,6 +111,10 @@ impl Page {
         self.number * PAGE_SIZE
     }
 
+    fn p5_index(&self) -> usize {
+        (self.number >> 36) & 0o777
+    }
+
     fn p4_index(&self) -> usize {
         (self.number >> 27) & 0o777
     }
@@ -80,6 +130,16 @@ impl Page {
     fn p1_index(&self) -> usize {
         (self.number >> 0) & 0o777
     }
+
+    // Higher half pages are shared by all address spaces
+    pub fn is_kernel(&self) -> bool {
+        self.start_address() >> 63 == 1
+    }
+
+    // Index into the top level table, P5 or P4 depending on the paging mode
+    fn top_index(&self) -> usize {
+        if paging_levels() == 5 { self.p5_index() } else { self.p4_index() }
+    }
 }
 
 pub struct ActivePageTable {
@@ -94,19 +154,19 @@ impl Deref for ActivePageTable {
 }
 
 impl DerefMut for ActivePageTable {
-    type Target = Mapper;
     fn deref_mut(&mut self) -> &mut Mapper {
         &mut self.mapper
     }
 }
 
 impl ActivePageTable {
-    unsafe fn new() -> ActivePageTable {
+    pub unsafe fn new() -> ActivePageTable {
         ActivePageTable{
             mapper: Mapper::new(),
         }
     }
 
+    #[cfg(not(feature = "direct-map"))]
     pub fn with<F>(&mut self,
                    inactive_table: &mut InactivePageTable,
                    temporary_page: &mut TemporaryPage,
@@ -116,25 +176,80 @@ impl ActivePageTable {
 		use x86_64::instructions::tlb;
 		use x86_64::registers::control_regs;
 
-        let active_table_backup = Frame::from_address(unsafe { control_regs::cr3() } as usize, 1);
-        let p4_table = temporary_page.map_table_frame(active_table_backup.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
-		// overwrite recursive mapping to point to the inactive page table
-		self.p4_mut()[511].set(inactive_table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        // Translations cached under the inactive table's PCID may not match what `f` changes
+        inactive_table.flushed_on = 0;
+        {
+            let backup = Frame::from_address(control_regs::cr3().0 as usize, 1);
+            // map the current P4 table so we can restore the recursive mapping afterwards
+            let p4_table = temporary_page.map_table_frame(backup.clone(), self);
+
+            // overwrite recursive mapping to point to the inactive page table
+            self.top_table_entry_mut(RECURSIVE_INDEX).set(inactive_table.p4_frame.clone(),
+                                                          EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            // flush translation lookaside buffer cache to clear old translations
+            tlb::flush_all();
 
-		// flush translation lookaside buffer cache to clear old translations
-		tlb::flush_all();
+            // re-execute f with new context
+            f(self);
 
-		// re-execute f with new context
-		f(self);
+            // restore recursive mapping to the original P4 table
+            p4_table[RECURSIVE_INDEX].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            tlb::flush_all();
+        }
+        temporary_page.unmap(self);
+    }
+
+    // With the direct map every table is reachable through `PHYSICAL_MEMORY_OFFSET`, so there is
+    // no need to touch the recursive entry or flush the TLB to edit an inactive table.
+    #[cfg(feature = "direct-map")]
+    pub fn with<F>(&mut self,
+                   inactive_table: &mut InactivePageTable,
+                   _temporary_page: &mut TemporaryPage,
+                   f: F)
+        where F: FnOnce(&mut Mapper)
+    {
+        inactive_table.flushed_on = 0;
+        let mut mapper = unsafe { Mapper::from_table_frame(&inactive_table.p4_frame) };
+        f(&mut mapper);
+    }
+
+    // Makes `new_table` the active table and returns the previously active one. With PCIDs the
+    // TLB entries of both address spaces survive the switch, so switching back is cheap.
+    pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
+        use x86_64::registers::control_regs;
+
+        let cpu = pcid::cpu_mask();
+        // Only this CPU is known to have no stale entries for the outgoing PCID
+        let old_table = InactivePageTable {
+            p4_frame: Frame::from_address(control_regs::cr3().0 as usize, 1),
+            pcid: pcid::current(),
+            flushed_on: cpu,
+        };
+        unsafe {
+            pcid::load_cr3(new_table.p4_frame.start_address(), new_table.pcid,
+                           new_table.flushed_on & cpu == 0);
+        }
+        // The PCID of the new table is read back from CR3 once it gets switched out again, the
+        // table itself is owned by CR3 until then
+        mem::forget(new_table);
+        old_table
     }
 }
 
 pub struct InactivePageTable {
     p4_frame: Frame,
+    // TLB tag of this address space, 0 if it has none (no PCID support or all ids taken)
+    pcid: u16,
+    // One bit per CPU (see `pcid::cpu_mask`) whose cached translations for `pcid` are known to
+    // be current. Switching to this table on any other CPU flushes them first. Empty for a
+    // freshly assigned, maybe recycled, PCID and after every change made through `with`.
+    flushed_on: u64,
 }
 
 impl InactivePageTable {
-    // Creates valid `InactivePageTable`s that are zero'ed and recursively mapped
+    // Creates valid `InactivePageTable`s that are recursively mapped and share the kernel half
+    // with the active table, the user half is empty
+    #[cfg(not(feature = "direct-map"))]
     pub fn new(frame: Frame,
                active_table: &mut ActivePageTable,
                temporary_page: &mut TemporaryPage)
@@ -148,14 +263,152 @@ impl InactivePageTable {
             // zero out the table
             table.zero();
             // set up recursive mapping
-            table[511].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            table[RECURSIVE_INDEX].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            share_kernel_half(table, active_table);
         }
         temporary_page.unmap(active_table);
 
-        InactivePageTable { p4_frame: frame }
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: pcid::allocate(),
+            flushed_on: 0,
+        }
+    }
+
+    // Zeroes the new table through the direct map. The recursive entry is still set up so code
+    // that relies on it keeps working once the table becomes active.
+    #[cfg(feature = "direct-map")]
+    pub fn new(frame: Frame,
+               active_table: &mut ActivePageTable,
+               _temporary_page: &mut TemporaryPage)
+        -> InactivePageTable
+    {
+        {
+            let p4_table = unsafe { &mut *table::table_from_frame::<Level4>(&frame) };
+            p4_table.zero();
+            p4_table[RECURSIVE_INDEX].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            // share the direct map, vmalloc and the kernel mapping with the active table
+            share_kernel_half(p4_table, active_table);
+        }
+
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: pcid::allocate(),
+            flushed_on: 0,
+        }
+    }
+
+    // Returns the user half of the address space to the allocator: every page table, every
+    // frame mapped without `SHARED` and finally the P4 frame and the PCID. The kernel half is
+    // shared with all other tables and stays untouched.
+    pub fn teardown<A>(mut self,
+                       active_table: &mut ActivePageTable,
+                       temporary_page: &mut TemporaryPage,
+                       allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        self.free(active_table, temporary_page, allocator);
+        mem::forget(self);
+    }
+
+    // `teardown` without consuming the table, which must not be used afterwards
+    pub fn free<A>(&mut self,
+                   active_table: &mut ActivePageTable,
+                   temporary_page: &mut TemporaryPage,
+                   allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        use x86_64::registers::control_regs;
+
+        let active_frame = Frame::from_address(control_regs::cr3().0 as usize, 1);
+        assert!(self.p4_frame.number != active_frame.number,
+                "tried to tear down the active page table");
+
+        active_table.with(self, temporary_page, |mapper| {
+            mapper.free_user_half(allocator);
+        });
+        pcid::free(self.pcid);
+        allocator.deallocate(self.p4_frame.clone());
+    }
+}
+
+// Tables that weren't torn down explicitly are torn down through the memory controller. If it
+// is locked, e.g. because the table is dropped while it's held, the table is leaked instead.
+impl Drop for InactivePageTable {
+    fn drop(&mut self) {
+        let freed = ::memory::try_with_controller(|memory_controller| {
+            memory_controller.free_page_table(self)
+        });
+        if freed.is_none() {
+            println!("leaking inactive page table at {:#x}, the memory controller is busy",
+                     self.p4_frame.start_address());
+        }
+    }
+}
+
+// Points the higher half entries of the top level `table` at the same lower level tables as the
+// active one, so kernel mappings are shared by every address space. The recursive entry is
+// left alone.
+fn share_kernel_half<L: TableLevel>(table: &mut Table<L>, active_table: &Mapper) {
+    for index in ENTRY_COUNT / 2..ENTRY_COUNT {
+        if index == RECURSIVE_INDEX {
+            continue;
+        }
+        if let Some(frame) = active_table.top_table_entry(index).frame_pointer() {
+            table[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        }
+    }
+}
+
+// Maps the physical memory past the first GiB (which boot.asm already maps) into the direct
+// map using 2MiB pages.
+#[cfg(feature = "direct-map")]
+pub fn init_direct_map<A>(memory_map_tag: &MemoryMapTag, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let physical_end = memory_map_tag.memory_areas()
+        .map(|area| (area.base_addr + area.length) as usize)
+        .max().unwrap();
+
+    let mut address = 512 * HUGE_PAGE_SIZE;
+    while address < physical_end {
+        active_table.map_to_2mib(Page::from_address(phys_to_virt(address)),
+                                 Frame::from_address(address, 512),
+                                 EntryFlags::WRITABLE,
+                                 CacheMode::WriteBack,
+                                 allocator);
+        address += HUGE_PAGE_SIZE;
     }
 }
 
+// Removes the identity map of the first GiB that boot.asm needed to jump to the higher half,
+// and without the `direct-map` feature also its mapping at the start of the higher half.
+// Must only be called once nothing references low addresses anymore.
+pub fn remove_identity_map() {
+    use x86_64::instructions::tlb;
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    active_table.top_table_entry_mut(0).set_unused();
+    if paging_levels() == 5 {
+        // boot.asm points P5 entries 0 and 511 at the same P4 table, so its entry 0 would keep
+        // the first GiB mapped at the start of the P5 entry 511 region
+        let p5 = unsafe { &mut *table::P5 };
+        if let Some(p4) = p5.next_table_mut(ENTRY_COUNT - 1) {
+            p4[0].set_unused();
+            if !cfg!(feature = "direct-map") {
+                p4[ENTRY_COUNT / 2].set_unused();
+            }
+        }
+    } else if !cfg!(feature = "direct-map") {
+        active_table.top_table_entry_mut(ENTRY_COUNT / 2).set_unused();
+    }
+    tlb::flush_all();
+}
+
 pub fn test_paging<A>(allocator: &mut A)
 where
     A: FrameAllocator,
@@ -170,8 +423,5 @@ where
 		*(Page::from_address(addr).start_address() as *const u64)
 	});
     page_table.unmap(page, allocator);
-    println!("Some = {:?}", page_table.translate(addr));
-	println!("{:#x}", unsafe {
-		*(Page::from_address(addr).start_address() as *const u64)
-	});
+    println!("None = {:?}", page_table.translate(addr));
 }
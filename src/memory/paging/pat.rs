@@ -0,0 +1,116 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use cpu::{self, Features};
+use memory::paging::EntryFlags;
+
+const IA32_PAT: u32 = 0x277;
+
+// Memory type encodings used in the IA32_PAT MSR
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+/**
+PAT layout programmed by `init`. Entries 0-3 match the power on defaults, so PCD/PWT keep their
+usual meaning for mappings that don't set the PAT bit. Entry 7 is changed from UC to WC.
+    Index   PAT PCD PWT     Type
+    0       0   0   0       WB
+    1       0   0   1       WT
+    2       0   1   0       UC-
+    3       0   1   1       UC
+    4       1   0   0       WB
+    5       1   0   1       WT
+    6       1   1   0       UC-
+    7       1   1   1       WC
+**/
+const PAT_LAYOUT: u64 = WB | WT << 8 | UC_MINUS << 16 | UC << 24 |
+                        WB << 32 | WT << 40 | UC_MINUS << 48 | WC << 56;
+
+static PAT_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    // Normal memory
+    WriteBack,
+    // Writes go straight to memory, reads are cached
+    WriteThrough,
+    // Strong uncacheable, for device registers
+    Uncacheable,
+    // Uncacheable, but can be overridden to WC by the MTRRs
+    UncachedMinus,
+    // Writes are buffered and combined, for framebuffers
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn pat_index(self) -> u8 {
+        match self {
+            CacheMode::WriteBack => 0,
+            CacheMode::WriteThrough => 1,
+            CacheMode::UncachedMinus => 2,
+            CacheMode::Uncacheable => 3,
+            // Without PAT, entry 7 is UC which is the closest safe choice
+            CacheMode::WriteCombining => 7,
+        }
+    }
+
+    // PAT/PCD/PWT bits for a 4KiB (P1) entry
+    pub fn flags(self) -> EntryFlags {
+        let index = self.pat_index();
+        let mut flags = self.pcd_pwt_flags(index);
+        if index & 0b100 != 0 && PAT_ENABLED.load(Ordering::Relaxed) {
+            flags |= EntryFlags::PAT;
+        }
+        flags
+    }
+
+    // PAT/PCD/PWT bits for a huge (P2/P3) entry, where the PAT bit moves to bit 12
+    pub fn huge_flags(self) -> EntryFlags {
+        let index = self.pat_index();
+        let mut flags = self.pcd_pwt_flags(index);
+        if index & 0b100 != 0 && PAT_ENABLED.load(Ordering::Relaxed) {
+            flags |= EntryFlags::HUGE_PAT;
+        }
+        flags
+    }
+
+    fn pcd_pwt_flags(self, index: u8) -> EntryFlags {
+        let mut flags = EntryFlags::empty();
+        if index & 0b001 != 0 {
+            flags |= EntryFlags::WRITE_THROUGH;
+        }
+        if index & 0b010 != 0 {
+            flags |= EntryFlags::NO_CACHE;
+        }
+        flags
+    }
+}
+
+// Programs the IA32_PAT MSR with `PAT_LAYOUT`. Must run before any WC mapping is created.
+pub fn init() {
+    use x86_64::instructions::tlb;
+    use x86_64::registers::msr::wrmsr;
+
+    if !cpu::has(Features::PAT) {
+        println!("PAT not supported, write combining falls back to uncacheable");
+        return;
+    }
+    unsafe {
+        wrmsr(IA32_PAT, PAT_LAYOUT);
+        // Drop cached lines and translations that were created with the old types
+        asm!("wbinvd" :::: "volatile");
+    }
+    tlb::flush_all();
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+// The PAT MSR is per CPU, application processors need the same layout as the BSP
+pub fn init_ap() {
+    use x86_64::registers::msr::wrmsr;
+
+    if PAT_ENABLED.load(Ordering::Relaxed) {
+        unsafe { wrmsr(IA32_PAT, PAT_LAYOUT) };
+    }
+}
@@ -0,0 +1,121 @@
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use cpu::{self, Features, read_cr4, write_cr4};
+use interrupts;
+use memory::PhysicalAddress;
+use percpu;
+use sync::Mutex;
+
+const CR4_PCIDE: u64 = 1 << 17;
+const CR4_PGE: u64 = 1 << 7;
+// Keep the TLB entries of the new PCID when loading CR3
+const CR3_NO_FLUSH: u64 = 1 << 63;
+const CR3_PCID_MASK: u64 = 0xfff;
+
+const PCID_COUNT: usize = 4096;
+
+static PCID_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+// Bit n is set while PCID n is owned by an address space. PCID 0 is never handed out (`init`
+// reserves it), it tags the boot page table and every table we couldn't give an own PCID.
+static ALLOCATED: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new([0; PCID_COUNT / 64]);
+
+// Turns on CR4.PCIDE if the CPU supports PCIDs. CR3 has to carry PCID 0 at this
+// point, which is always the case for the boot table.
+pub fn init() {
+    if !cpu::has(Features::PCID) {
+        println!("PCID not supported, every address space switch flushes the TLB");
+        return;
+    }
+    ALLOCATED.lock()[0] |= 1;
+    unsafe { write_cr4(read_cr4() | CR4_PCIDE) };
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Application processors start with the BSP's page table, which uses PCID 0
+pub fn init_ap() {
+    if enabled() {
+        unsafe { write_cr4(read_cr4() | CR4_PCIDE) };
+    }
+}
+
+pub fn enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+// Hands out an unused PCID, or 0 if PCIDs aren't supported or all of them are taken. A recycled
+// PCID may have stale entries on every CPU the previous owner ran on, the new owner starts with
+// an empty `InactivePageTable::flushed_on` so each CPU flushes them on its first switch.
+pub fn allocate() -> u16 {
+    if !enabled() {
+        return 0;
+    }
+    let mut allocated = ALLOCATED.lock();
+    for (i, word) in allocated.iter_mut().enumerate() {
+        if *word != !0 {
+            let bit = (!*word).trailing_zeros() as usize;
+            *word |= 1 << bit;
+            return (i * 64 + bit) as u16;
+        }
+    }
+    0
+}
+
+pub fn free(pcid: u16) {
+    if pcid == 0 {
+        return;
+    }
+    let pcid = pcid as usize;
+    let mut allocated = ALLOCATED.lock();
+    assert!(allocated[pcid / 64] & (1 << (pcid % 64)) != 0, "double free of PCID {}", pcid);
+    allocated[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+// Bit of the calling CPU in `InactivePageTable::flushed_on`. Before the per-CPU area is loaded
+// only the bootstrap processor runs.
+pub fn cpu_mask() -> u64 {
+    let cpu = if percpu::loaded() { percpu::cpu_id() } else { 0 };
+    1 << cpu
+}
+
+// Drops the cached translations of every PCID on this CPU. Kernel mappings are cached under
+// each PCID that used them while `invlpg` only reaches the current one, so this has to follow
+// every change to an existing kernel mapping. Toggling CR4.PGE flushes all PCIDs.
+pub fn flush_all_pcids() {
+    if !enabled() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let cr4 = read_cr4();
+        unsafe {
+            write_cr4(cr4 ^ CR4_PGE);
+            write_cr4(cr4);
+        }
+    });
+}
+
+// PCID of the active address space
+pub fn current() -> u16 {
+    if !enabled() {
+        return 0;
+    }
+    (read_cr3() & CR3_PCID_MASK) as u16
+}
+
+// Loads `p4_address` into CR3 tagged with `pcid`. Unless `flush` is set, the TLB entries cached
+// for `pcid` survive. PCID 0 is shared and always flushed.
+pub unsafe fn load_cr3(p4_address: PhysicalAddress, pcid: u16, flush: bool) {
+    let mut value = p4_address as u64;
+    if enabled() {
+        value |= pcid as u64 & CR3_PCID_MASK;
+        if !flush && pcid != 0 {
+            value |= CR3_NO_FLUSH;
+        }
+    }
+    asm!("mov $0, %cr3" :: "r"(value) : "memory" : "volatile");
+}
+
+fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr3, $0" : "=r"(value) ::: "volatile") };
+    value
+}
@@ -2,7 +2,11 @@ use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
 
 use memory::FrameAllocator;
-use memory::paging::{Page, ENTRY_COUNT};
+#[cfg(feature = "direct-map")]
+use memory::{Frame, phys_to_virt};
+use memory::paging::ENTRY_COUNT;
+#[cfg(not(feature = "direct-map"))]
+use memory::paging::sign_extend;
 use memory::paging::entry::{Entry, EntryFlags};
 
 pub struct Table<L>
@@ -42,6 +46,10 @@ where
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L>
@@ -55,13 +63,16 @@ where
             .map(|addr| unsafe { &*(addr as *const _) })
     }
 
-    pub fn next_table_mut<'a>(&'a self, index: usize) -> Option<&'a mut Table<L::NextLevel>>
+    pub fn next_table_mut<'a>(&'a mut self, index: usize) -> Option<&'a mut Table<L::NextLevel>>
     {
         self.next_table_address(index)
             .map(|addr| unsafe { &mut *(addr as *mut _) })
     }
 
-    pub fn next_table_or_create<'a, A>(&'a mut self, index: usize, allocator: &mut A) -> &'a mut Table<L::NextLevel>
+    // Also sets `flags` on the entry. The CPU combines the flags of all levels, so e.g. a user
+    // page needs USER_ACCESSIBLE on every table above it.
+    pub fn next_table_or_create<'a, A>(&'a mut self, index: usize, flags: EntryFlags,
+                                       allocator: &mut A) -> &'a mut Table<L::NextLevel>
     where
         A: FrameAllocator,
     {
@@ -70,13 +81,15 @@ where
             assert!(!self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
                     "huge pages is disabled in the mapper");
             let frame = allocator.allocate(1).expect("no more physical memory frames are available");
-            println!("creating next table index: {}, frame: {:?}", index, frame);
-            self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE | flags);
             self.next_table_mut(index).unwrap().zero();
+        } else {
+            self.entries[index].insert_flags(flags);
         }
         self.next_table_mut(index).unwrap()
     }
 
+    #[cfg(not(feature = "direct-map"))]
     fn next_table_address(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE) {
@@ -85,22 +98,49 @@ where
             // address (before the 12 bit index offset) points at the NEXT table
             // We shift the table address over and shift the index into this 9 bit space so it
             // becomes the pointer to the next table
-            Some((table_address << 9) | index << 12)
+            // The shift moves the top level index into the sign extension bits, so the result
+            // has to be sign extended again (the recursive index 510 isn't all ones).
+            Some(sign_extend((table_address << 9) | index << 12))
+        } else {
+            None
+        }
+    }
+
+    #[cfg(feature = "direct-map")]
+    fn next_table_address(&self, index: usize) -> Option<usize> {
+        let entry_flags = self[index].flags();
+        if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE) {
+            // Every frame is reachable through the direct map, so the next table lives at its
+            // physical address plus the offset. This works for inactive tables as well.
+            self[index].frame_pointer()
+                .map(|frame| phys_to_virt(frame.start_address()))
         } else {
             None
         }
     }
 }
 
-pub const P4: *mut Table<Level4> = 0o177777_777_777_777_777_0000 as *mut _;
+// Address of the P4 table through the recursive entry (510)
+pub const P4: *mut Table<Level4> = 0o177777_776_776_776_776_0000 as *mut _;
+// Address of the P5 table through the recursive entry (510) when 5-level paging is enabled.
+// The sign extension shrinks to bits 57-63.
+pub const P5: *mut Table<Level5> = 0o177_776_776_776_776_776_0000 as *mut _;
+
+// Returns the table in the given physical frame through the direct map
+#[cfg(feature = "direct-map")]
+pub unsafe fn table_from_frame<L: TableLevel>(frame: &Frame) -> *mut Table<L> {
+    phys_to_virt(frame.start_address()) as *mut _
+}
 
 pub trait TableLevel {}
 
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -110,6 +150,9 @@ pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
 
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -1,17 +1,23 @@
 use super::{ActivePageTable, Page, VirtualAddress};
+use super::entry::EntryFlags;
+use super::table::{Table, Level1};
 use memory::{Frame, FrameAllocator};
 
 struct TinyAllocator([Option<Frame>; 3]);
 
-impl FrameAllocator for TinyAllocator {
-    pub fn new<A>(allocator: A) -> TinyAllocator {
+impl TinyAllocator {
+    fn new<A>(allocator: &mut A) -> TinyAllocator
+        where A: FrameAllocator
+    {
         // Allocate some 1 page frames
         let mut f = || allocator.allocate(1);
         let frames = [f(), f(), f()];
         TinyAllocator(frames)
     }
+}
 
-    fn allocate(&mut self, num_pages: usize) -> Option<Frame> {
+impl FrameAllocator for TinyAllocator {
+    fn allocate(&mut self, _num_pages: usize) -> Option<Frame> {
         // Just going to assume the following are 1 page sized frames
         // Return the first unused frame
         for frame_option in &mut self.0 {
@@ -19,6 +25,7 @@ impl FrameAllocator for TinyAllocator {
                 return frame_option.take();
             }
         }
+        None
     }
 
     fn deallocate(&mut self, frame: Frame) {
@@ -28,6 +35,7 @@ impl FrameAllocator for TinyAllocator {
                 return;
             }
         }
+        panic!("Tiny allocator can hold only 3 frames.");
     }
 }
 
@@ -52,23 +60,33 @@ impl TemporaryPage {
                active_table: &mut ActivePageTable)
         -> VirtualAddress
     {
-        use super::entry::WRITABLE;
-
         assert!(active_table.translate_page(self.page).is_none(),
                 "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, self.allocator);
+        active_table.map_to(self.page, frame, EntryFlags::WRITABLE, &mut self.allocator);
         self.page.start_address()
     }
 
     /// Unmaps the temporary page in the active table.
+    /// The mapped frame is not owned by the temporary page, so it isn't freed.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, self.allocator)
+        active_table.unmap_frame(self.page);
+    }
+
+    /// Gives the frames set aside for page tables that weren't needed back to `allocator`.
+    pub fn free<A>(mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        while let Some(frame) = self.allocator.allocate(1) {
+            allocator.deallocate(frame);
+        }
     }
 
+    /// Maps the temporary page to the given page table frame in the active table.
+    /// Returns a reference to the now mapped table.
     pub fn map_table_frame(&mut self,
                            frame: Frame,
                            active_table: &mut ActivePageTable)
         -> &mut Table<Level1> {
-        unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1> }
+        unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1>) }
     }
 }
@@ -0,0 +1,151 @@
+use core::slice;
+
+use block::{BlockDevice, BLOCK_SIZE};
+use memory::{PAGE_SIZE, FrameAllocator};
+use memory::flush_tlb_everywhere;
+use memory::paging::{EntryFlags, Mapper, Page};
+use sync::Mutex;
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+// Max number of page sized slots (16MiB), there is no heap to size the bitmap at runtime
+const MAX_SLOTS: usize = 4096;
+
+/**
+Swap area on a block device, divided into page sized slots. A swapped out page keeps a
+non-present entry that records its slot (see `Entry::set_swapped`), so the fault handler can
+read it back.
+**/
+struct SwapArea {
+    device: &'static Mutex<BlockDevice + Send>,
+    slots: usize,
+    // Bit n is set while slot n holds a page
+    used: [u64; MAX_SLOTS / 64],
+}
+
+impl SwapArea {
+    fn allocate_slot(&mut self) -> Option<usize> {
+        for (i, word) in self.used.iter_mut().enumerate() {
+            if *word != !0 {
+                let slot = i * 64 + (!*word).trailing_zeros() as usize;
+                if slot >= self.slots {
+                    return None;
+                }
+                *word |= 1 << (slot % 64);
+                return Some(slot);
+            }
+        }
+        None
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        assert!(self.used[slot / 64] & (1 << (slot % 64)) != 0, "double free of swap slot {}", slot);
+        self.used[slot / 64] &= !(1 << (slot % 64));
+    }
+}
+
+// Only taken with `MEMORY_CONTROLLER` held. Interrupt handlers reach it through the controller,
+// whose rules (see there) keep them from interrupting a holder, so it needs no `IrqLock`.
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+// Uses `device` as the swap area, replacing the previous one. Only safe while nothing is
+// swapped out.
+pub fn set_device(device: &'static Mutex<BlockDevice + Send>) {
+    let blocks = device.lock().block_count();
+    let slots = ::core::cmp::min(blocks / BLOCKS_PER_PAGE, MAX_SLOTS);
+    println!("swap: {} slots", slots);
+    *SWAP.lock() = Some(SwapArea{
+        device: device,
+        slots: slots,
+        used: [0; MAX_SLOTS / 64],
+    });
+}
+
+// Writes `page` to a free swap slot, replaces its entry with a swap entry and frees the frame.
+// Returns false if the page isn't swappable (not present, shared) or there is no free slot.
+pub fn swap_out<A>(page: Page, mapper: &mut Mapper, allocator: &mut A) -> bool
+where
+    A: FrameAllocator,
+{
+    use x86_64::instructions::tlb;
+    use x86_64::VirtualAddress;
+
+    let mut swap = SWAP.lock();
+    let area = match swap.as_mut() {
+        Some(area) => area,
+        None => return false,
+    };
+    let entry = match mapper.entry_mut(page) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let flags = entry.flags();
+    let frame = match entry.frame_pointer() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    if flags.contains(EntryFlags::SHARED) {
+        return false;
+    }
+    let slot = match area.allocate_slot() {
+        Some(slot) => slot,
+        None => return false,
+    };
+
+    // The page is still mapped in the active table, so we write it out through its own address
+    // before unmapping it. This is only safe as long as no other CPU can write to it meanwhile.
+    let contents = unsafe {
+        slice::from_raw_parts(page.start_address() as *const u8, PAGE_SIZE)
+    };
+    if area.device.lock().write_blocks(slot * BLOCKS_PER_PAGE, contents).is_err() {
+        area.free_slot(slot);
+        return false;
+    }
+
+    entry.set_swapped(slot, flags);
+    tlb::flush(VirtualAddress(page.start_address()));
+    flush_tlb_everywhere();
+    allocator.deallocate(frame);
+    true
+}
+
+// Reads a swapped out page back into a fresh frame. Returns false if `page` isn't swapped out
+// or memory is exhausted.
+pub fn swap_in<A>(page: Page, mapper: &mut Mapper, allocator: &mut A) -> bool
+where
+    A: FrameAllocator,
+{
+    let mut swap = SWAP.lock();
+    let area = match swap.as_mut() {
+        Some(area) => area,
+        None => return false,
+    };
+    let entry = match mapper.entry_mut(page) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let slot = match entry.swap_slot() {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let frame = match allocator.allocate(1) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    // Map the frame first so we can read straight into the page. The entry was non-present, so
+    // there is no stale translation to flush.
+    let flags = entry.swapped_flags();
+    entry.set(frame, flags | EntryFlags::PRESENT);
+    let contents = unsafe {
+        slice::from_raw_parts_mut(page.start_address() as *mut u8, PAGE_SIZE)
+    };
+    area.device.lock().read_blocks(slot * BLOCKS_PER_PAGE, contents)
+        .expect("failed to read page from swap");
+    area.free_slot(slot);
+    true
+}
+
+// Releases the slot of a swapped out page whose mapping goes away
+pub fn free_slot(slot: usize) {
+    SWAP.lock().as_mut().expect("swap slot without a swap area").free_slot(slot);
+}
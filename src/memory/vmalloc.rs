@@ -0,0 +1,215 @@
+use memory::{PAGE_SIZE, PhysicalAddress, VirtualAddress, Frame, FrameAllocator, MemoryController};
+use memory::flush_tlb_everywhere;
+use memory::paging::{ActivePageTable, CacheMode, EntryFlags, Page};
+use sync::Mutex;
+
+// Kernel region for virtually contiguous mappings (P4 entry 384, 512GiB). It sits between the
+// direct physical map (P4 entry 256) and the recursive/kernel entries (510/511).
+pub const VMALLOC_START: VirtualAddress = 0xffff_c000_0000_0000;
+pub const VMALLOC_END: VirtualAddress = 0xffff_c080_0000_0000 - PAGE_SIZE;
+// The last page of the region, where `TemporaryPage` maps frames of inactive page tables. Being
+// below the pre-allocated top level entry it needs no tables outside the shared kernel half.
+pub const TEMPORARY_PAGE: VirtualAddress = VMALLOC_END;
+
+// Unmapped pages left after every area so an overrun faults instead of corrupting the
+// neighbouring allocation
+const GUARD_PAGES: usize = 1;
+// Max number of live areas, there is no heap to grow this list
+const MAX_AREAS: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AreaKind {
+    // Backed by frames we allocated and own
+    Vmalloc,
+    // Points at frames owned by a device
+    Ioremap,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    start: VirtualAddress,
+    pages: usize,  // Mapped pages, not counting the guard pages
+    kind: AreaKind,
+}
+
+impl Area {
+    // End of the area including its guard pages
+    fn end(&self) -> VirtualAddress {
+        self.start + (self.pages + GUARD_PAGES) * PAGE_SIZE
+    }
+}
+
+/**
+First fit allocator for virtual address ranges inside `[VMALLOC_START, VMALLOC_END)`.
+Live areas are kept sorted by start address so gaps can be found in a single pass.
+**/
+pub struct VirtualRangeAllocator {
+    areas: [Option<Area>; MAX_AREAS],
+    count: usize,
+}
+
+impl VirtualRangeAllocator {
+    pub const fn new() -> VirtualRangeAllocator {
+        VirtualRangeAllocator{
+            areas: [None; MAX_AREAS],
+            count: 0,
+        }
+    }
+
+    // Reserves `pages` pages plus guard pages and returns the start of the range
+    fn allocate(&mut self, pages: usize, kind: AreaKind) -> Option<VirtualAddress> {
+        if pages == 0 || self.count == MAX_AREAS {
+            return None;
+        }
+        let size = (pages + GUARD_PAGES) * PAGE_SIZE;
+
+        // Find the first gap that fits and the position to insert the new area at
+        let mut candidate = VMALLOC_START;
+        let mut position = self.count;
+        for i in 0..self.count {
+            let area = self.areas[i].unwrap();
+            if candidate + size <= area.start {
+                position = i;
+                break;
+            }
+            candidate = area.end();
+        }
+        if candidate + size > VMALLOC_END {
+            return None;
+        }
+
+        // Shift the following areas up by one to keep the list sorted
+        let mut i = self.count;
+        while i > position {
+            self.areas[i] = self.areas[i - 1];
+            i -= 1;
+        }
+        self.areas[position] = Some(Area{
+            start: candidate,
+            pages: pages,
+            kind: kind,
+        });
+        self.count += 1;
+        Some(candidate)
+    }
+
+    // Releases the area starting at `start` and returns it
+    fn free(&mut self, start: VirtualAddress) -> Option<Area> {
+        let position = (0..self.count).find(|&i| self.areas[i].unwrap().start == start);
+        position.map(|position| {
+            let area = self.areas[position].take().unwrap();
+            for i in position..self.count - 1 {
+                self.areas[i] = self.areas[i + 1];
+            }
+            self.areas[self.count - 1] = None;
+            self.count -= 1;
+            area
+        })
+    }
+}
+
+static VMALLOC: Mutex<VirtualRangeAllocator> = Mutex::new(VirtualRangeAllocator::new());
+
+// Creates the top level entry of the vmalloc region. It has to exist before the first address
+// space is created from the kernel's top level table, otherwise that one never sees AP stacks,
+// per-CPU areas and the like.
+pub fn init<A>(active_table: &mut ActivePageTable, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    active_table.create_top_table_entry(VMALLOC_START, allocator);
+}
+
+fn pages_for(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+// Allocates `size` bytes of virtually contiguous memory. Every page is backed by its own frame,
+// so the memory is not physically contiguous.
+pub fn vmalloc(size: usize, controller: &mut MemoryController) -> Option<VirtualAddress> {
+    let pages = pages_for(size);
+    let start = match VMALLOC.lock().allocate(pages, AreaKind::Vmalloc) {
+        Some(start) => start,
+        None => return None,
+    };
+
+    for i in 0..pages {
+        let page = Page::from_address(start + i * PAGE_SIZE);
+        let mapped = match controller.allocate_frame() {
+            Some(frame) => match controller.map_to(page, frame, EntryFlags::WRITABLE) {
+                Ok(()) => true,
+                Err(frame) => {
+                    controller.allocator.deallocate(frame);
+                    false
+                }
+            },
+            None => false,
+        };
+        if !mapped {
+            // Out of frames, undo what we mapped so far
+            for j in 0..i {
+                controller.active_table.unmap(Page::from_address(start + j * PAGE_SIZE),
+                                              &mut controller.allocator);
+            }
+            VMALLOC.lock().free(start);
+            return None;
+        }
+    }
+    Some(start)
+}
+
+// Unmaps and frees memory returned by `vmalloc`
+pub fn vfree<A>(address: VirtualAddress, active_table: &mut ActivePageTable, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let area = VMALLOC.lock().free(address)
+        .expect("vfree of an address that wasn't vmalloc'ed");
+    assert!(area.kind == AreaKind::Vmalloc, "vfree of an ioremap'ed address");
+    for i in 0..area.pages {
+        active_table.unmap(Page::from_address(area.start + i * PAGE_SIZE), allocator);
+    }
+    flush_tlb_everywhere();
+}
+
+// Maps the physical range `[address, address + size)` into the vmalloc region with the given
+// memory type. The returned address keeps the offset of `address` within its page.
+pub fn ioremap(address: PhysicalAddress, size: usize, mode: CacheMode,
+               controller: &mut MemoryController)
+    -> Option<VirtualAddress>
+{
+    let offset = address % PAGE_SIZE;
+    let first_frame = address - offset;
+    let pages = pages_for(size + offset);
+    let start = match VMALLOC.lock().allocate(pages, AreaKind::Ioremap) {
+        Some(start) => start,
+        None => return None,
+    };
+
+    let flags = EntryFlags::WRITABLE | EntryFlags::SHARED | mode.flags();
+    for i in 0..pages {
+        let page = Page::from_address(start + i * PAGE_SIZE);
+        let frame = Frame::from_address(first_frame + i * PAGE_SIZE, 1);
+        // The device frames aren't ours, on failure they are just forgotten
+        if controller.map_to(page, frame, flags).is_err() {
+            for j in 0..i {
+                controller.active_table.unmap_frame(Page::from_address(start + j * PAGE_SIZE));
+            }
+            VMALLOC.lock().free(start);
+            return None;
+        }
+    }
+    Some(start + offset)
+}
+
+// Removes a mapping created by `ioremap`. The device frames are not freed.
+pub fn iounmap(address: VirtualAddress, active_table: &mut ActivePageTable) {
+    let start = address - address % PAGE_SIZE;
+    let area = VMALLOC.lock().free(start)
+        .expect("iounmap of an address that wasn't ioremap'ed");
+    assert!(area.kind == AreaKind::Ioremap, "iounmap of a vmalloc'ed address");
+    for i in 0..area.pages {
+        active_table.unmap_frame(Page::from_address(area.start + i * PAGE_SIZE));
+    }
+    flush_tlb_everywhere();
+}
@@ -0,0 +1,170 @@
+use memory::{PAGE_SIZE, VirtualAddress};
+use memory::paging::{EntryFlags, Mapper, Page, MAX_AGE};
+
+// Max number of tracked regions, there is no heap to grow this list
+const MAX_REGIONS: usize = 32;
+// A page that wasn't accessed for this many scans in a row counts as cold
+pub const COLD_AGE: u8 = 4;
+
+/**
+Per-region counters, updated by every `WorkingSet::scan`:
+    hot         pages accessed since the previous scan
+    cold        pages unaccessed for at least `COLD_AGE` scans
+    dirty       pages written since the previous scan
+    heat        decaying average of `hot`, halves every scan
+**/
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtualAddress,
+    pub pages: usize,
+    pub hot: usize,
+    pub cold: usize,
+    pub dirty: usize,
+    pub heat: usize,
+}
+
+/**
+Working set estimation based on the ACCESSED and DIRTY bits the CPU sets in the P1 entries.
+Every scan samples and clears both bits. The per-page age (scans since the last access) lives
+in the available entry bits 52-55, so no extra memory is needed per page. DIRTY is preserved in
+`SOFT_DIRTY` so reclaim still knows whether a page has to be written back.
+Meant to be called periodically, every scan advances the age of untouched pages by one.
+**/
+pub struct WorkingSet {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl WorkingSet {
+    pub const fn new() -> WorkingSet {
+        WorkingSet{
+            regions: [None; MAX_REGIONS],
+        }
+    }
+
+    // Starts tracking `pages` pages from `start`. Returns false if the region table is full.
+    pub fn add_region(&mut self, start: VirtualAddress, pages: usize) -> bool {
+        assert!(start % PAGE_SIZE == 0, "region start is not page aligned");
+        for slot in self.regions.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(Region{
+                    start: start,
+                    pages: pages,
+                    hot: 0,
+                    cold: 0,
+                    dirty: 0,
+                    heat: 0,
+                });
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn remove_region(&mut self, start: VirtualAddress) {
+        for slot in self.regions.iter_mut() {
+            if slot.map_or(false, |region| region.start == start) {
+                *slot = None;
+            }
+        }
+    }
+
+    // Stops tracking every region in the lower half, for when the user address space goes away
+    pub fn remove_user_regions(&mut self) {
+        for slot in self.regions.iter_mut() {
+            if slot.map_or(false, |region| !Page::from_address(region.start).is_kernel()) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn region(&self, start: VirtualAddress) -> Option<Region> {
+        self.regions.iter()
+            .filter_map(|slot| *slot)
+            .find(|region| region.start == start)
+    }
+
+    // Samples and clears ACCESSED/DIRTY of every present page in every region
+    pub fn scan(&mut self, mapper: &mut Mapper) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        for region in self.regions.iter_mut().filter_map(|slot| slot.as_mut()) {
+            let (mut hot, mut cold, mut dirty) = (0, 0, 0);
+            for i in 0..region.pages {
+                let page = Page::from_address(region.start + i * PAGE_SIZE);
+                let entry = match mapper.entry_mut(page) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let flags = entry.flags();
+                if !flags.contains(EntryFlags::PRESENT) {
+                    continue;
+                }
+
+                if flags.contains(EntryFlags::ACCESSED) {
+                    entry.set_age(0);
+                    hot += 1;
+                } else {
+                    let age = entry.age();
+                    if age < MAX_AGE {
+                        entry.set_age(age + 1);
+                    }
+                    if age + 1 >= COLD_AGE {
+                        cold += 1;
+                    }
+                }
+                if flags.contains(EntryFlags::DIRTY) {
+                    entry.insert_flags(EntryFlags::SOFT_DIRTY);
+                    dirty += 1;
+                }
+
+                if flags.intersects(EntryFlags::ACCESSED | EntryFlags::DIRTY) {
+                    entry.remove_flags(EntryFlags::ACCESSED | EntryFlags::DIRTY);
+                    // The CPU only sets the bits again if the cached translation is gone
+                    tlb::flush(VirtualAddress(page.start_address()));
+                }
+            }
+            region.hot = hot;
+            region.cold = cold;
+            region.dirty = dirty;
+            region.heat = region.heat / 2 + hot;
+        }
+    }
+
+    // Fills `out` with present pages that weren't accessed for at least `min_age` scans, the
+    // coldest region first. Returns the number of pages written.
+    pub fn cold_pages(&self, mapper: &Mapper, min_age: u8, out: &mut [Page]) -> usize {
+        let mut regions = self.regions;
+        // Sort by heat with a simple insertion sort, there are only a few regions
+        for i in 1..MAX_REGIONS {
+            let mut j = i;
+            while j > 0 && heat(&regions[j]) < heat(&regions[j - 1]) {
+                regions.swap(j, j - 1);
+                j -= 1;
+            }
+        }
+
+        let mut count = 0;
+        for region in regions.iter().filter_map(|slot| slot.as_ref()) {
+            for i in 0..region.pages {
+                if count == out.len() {
+                    return count;
+                }
+                let page = Page::from_address(region.start + i * PAGE_SIZE);
+                let is_cold = mapper.entry(page).map_or(false, |entry| {
+                    entry.flags().contains(EntryFlags::PRESENT) && entry.age() >= min_age
+                });
+                if is_cold {
+                    out[count] = page;
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+}
+
+// Empty slots sort last
+fn heat(slot: &Option<Region>) -> usize {
+    slot.map_or(usize::max_value(), |region| region.heat)
+}
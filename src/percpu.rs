@@ -0,0 +1,208 @@
+use core::cell::UnsafeCell;
+use core::ptr;
+
+use x86_64::registers::msr::{rdmsr, wrmsr};
+
+use cpu;
+use interrupts;
+use memory::{MemoryController, VirtualAddress};
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/**
+Declares a per-CPU variable. Every CPU gets its own instance, starting out with the initial
+value given here:
+
+    per_cpu! {
+        static IRQ_DEPTH: usize = 0;
+    }
+
+The statics are collected in the `.percpu` section, which is only a template: `allocate` copies
+it for each CPU and `load` points the GS base at the copy.
+**/
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr;) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        static $name: $crate::percpu::PerCpu<$t> = $crate::percpu::PerCpu::new($init);
+    };
+}
+
+/**
+Start of every area, accessed from assembly at fixed offsets from the GS base:
+
+    offset  field
+    0       area           the area's own address, so that it can be found with a single
+                           GS relative load instead of reading the GS base MSR
+    8       kernel_stack   top of the stack the system call entry switches to
+    16      user_stack     user stack pointer while in a system call
+    24      user_return    kernel stack pointer to return to when the user program exits
+**/
+#[repr(C)]
+struct Header {
+    area: VirtualAddress,
+    kernel_stack: VirtualAddress,
+    user_stack: VirtualAddress,
+    user_return: VirtualAddress,
+}
+
+#[link_section = ".percpu.header"]
+static HEADER: PerCpu<Header> = PerCpu::new(Header{
+    area: 0,
+    kernel_stack: 0,
+    user_stack: 0,
+    user_return: 0,
+});
+
+per_cpu! {
+    // Number of the CPU, 0 for the bootstrap processor
+    static CPU_ID: usize = 0;
+}
+
+per_cpu! {
+    static APIC_ID: u32 = 0;
+}
+
+// Bounds of the template, see linker.ld
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>,
+}
+
+// Each CPU only ever touches its own instance
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> PerCpu<T> {
+        PerCpu{
+            template: UnsafeCell::new(value),
+        }
+    }
+
+    /**
+    Calls `f` with the calling CPU's instance. Interrupts are disabled in the meantime, so the
+    current code can't be preempted (and later migrated to another CPU) while it holds the
+    reference. Must not be nested for the same variable.
+    **/
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        interrupts::without_interrupts(|| f(unsafe { &mut *self.this_cpu() }))
+    }
+
+    fn this_cpu(&'static self) -> *mut T {
+        let offset = self.template.get() as usize - template_start();
+        (area_base() + offset) as *mut T
+    }
+}
+
+impl<T: Copy> PerCpu<T> {
+    pub fn get(&'static self) -> T {
+        self.with(|value| *value)
+    }
+
+    pub fn set(&'static self, value: T) {
+        self.with(|instance| *instance = value)
+    }
+}
+
+// Allocates and initializes a per-CPU area from the template
+pub fn allocate(memory_controller: &mut MemoryController) -> Option<VirtualAddress> {
+    let size = template_end() - template_start();
+    let area = match memory_controller.vmalloc(size) {
+        Some(area) => area,
+        None => return None,
+    };
+    unsafe {
+        ptr::copy_nonoverlapping(template_start() as *const u8, area as *mut u8, size);
+        ptr::write(area as *mut VirtualAddress, area);
+    }
+    Some(area)
+}
+
+/**
+Makes `area` the per-CPU area of the calling CPU. The kernel runs with the GS base pointing at
+it, while the other GS base MSR holds the user one, which is always 0. Every entry from user mode
+(`syscall` and interrupts with a ring 3 CS) does `swapgs`, and so does every way back to it.
+**/
+pub unsafe fn load(area: VirtualAddress, cpu: usize) {
+    wrmsr(IA32_GS_BASE, area as u64);
+    wrmsr(IA32_KERNEL_GS_BASE, 0);
+    CPU_ID.set(cpu);
+    APIC_ID.set(cpu::initial_apic_id());
+}
+
+// Whether the calling CPU runs with its per-CPU area as GS base: `load` ran and, in the few
+// instructions around a switch to or from user mode, `swapgs` brought in the kernel GS base
+pub fn loaded() -> bool {
+    unsafe { rdmsr(IA32_GS_BASE) != 0 }
+}
+
+/**
+Brings in the kernel GS base for an interrupt handler if `user_gs` says the interrupted code ran
+with the user one, and restores that when dropped. Has to be created before the handler touches
+per-CPU data. A handler that ends the user program never drops it and leaves the kernel GS base
+loaded, which is what `user::exit` continues with.
+**/
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    #[inline(always)]
+    pub fn enter(user_gs: bool) -> KernelGs {
+        if user_gs {
+            unsafe { asm!("swapgs" :::: "volatile") };
+        }
+        KernelGs{
+            swapped: user_gs,
+        }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs" :::: "volatile") };
+        }
+    }
+}
+
+// Sets the stack the calling CPU's system call entry runs on, see `syscall::init_ap`
+pub fn set_kernel_stack(top: VirtualAddress) {
+    HEADER.with(|header| header.kernel_stack = top);
+}
+
+// Top of the calling CPU's system call stack, 0 before `set_kernel_stack`
+pub fn kernel_stack() -> VirtualAddress {
+    HEADER.with(|header| header.kernel_stack)
+}
+
+pub fn cpu_id() -> usize {
+    CPU_ID.get()
+}
+
+pub fn apic_id() -> u32 {
+    APIC_ID.get()
+}
+
+fn area_base() -> VirtualAddress {
+    let base: VirtualAddress;
+    unsafe { asm!("movq %gs:0, $0" : "=r"(base) ::: "volatile") };
+    base
+}
+
+fn template_start() -> VirtualAddress {
+    unsafe { &__percpu_start as *const u8 as VirtualAddress }
+}
+
+fn template_end() -> VirtualAddress {
+    unsafe { &__percpu_end as *const u8 as VirtualAddress }
+}
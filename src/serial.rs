@@ -0,0 +1,98 @@
+use core::fmt;
+
+use x86_64::instructions::port::{inb, outb};
+
+use sync::IrqLock;
+
+// I/O port base of the first 16550 UART, which QEMU connects with `-serial`
+const COM1: u16 = 0x3f8;
+
+/**
+UART registers, as offsets from the port base:
+    Offset  Name                    Meaning
+    0       data                    received/transmitted byte, divisor low byte while DLAB is set
+    1       interrupt enable        divisor high byte while DLAB is set
+    2       FIFO control
+    3       line control            bits 0-1 word length, bit 7 DLAB (divisor latch access)
+    4       modem control
+    5       line status             bit 5 transmit holding register empty
+**/
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const DLAB: u8 = 1 << 7;
+const EIGHT_BITS: u8 = 0b11;
+// Enable and clear both FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE: u8 = 0xc7;
+// DTR, RTS and OUT2
+const MODEM_READY: u8 = 0x0b;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// 115200 / 3 = 38400 baud
+const BAUD_DIVISOR: u16 = 3;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+// Interrupt handlers and panics print here as well
+static COM1_PORT: IrqLock<SerialPort> = IrqLock::new(SerialPort{ base: COM1 });
+
+impl SerialPort {
+    fn init(&mut self) {
+        unsafe {
+            // Polled output only
+            outb(self.base + INTERRUPT_ENABLE, 0);
+            outb(self.base + LINE_CONTROL, DLAB);
+            outb(self.base + DATA, BAUD_DIVISOR as u8);
+            outb(self.base + INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+            // 8 data bits, no parity, one stop bit
+            outb(self.base + LINE_CONTROL, EIGHT_BITS);
+            outb(self.base + FIFO_CONTROL, FIFO_ENABLE);
+            outb(self.base + MODEM_CONTROL, MODEM_READY);
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while inb(self.base + LINE_STATUS) & TRANSMIT_EMPTY == 0 {}
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Terminals expect CRLF
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+pub fn init() {
+    COM1_PORT.lock().init();
+}
+
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    COM1_PORT.lock().write_fmt(args).unwrap();
+}
+
+// Like `vga_buffer::print_panic`. The port has no state worth protecting, so if the lock is
+// held the output goes to it directly.
+pub fn print_panic(args: fmt::Arguments) {
+    use core::fmt::Write;
+    match COM1_PORT.try_lock() {
+        Some(mut port) => port.write_fmt(args).unwrap(),
+        None => SerialPort{ base: COM1 }.write_fmt(args).unwrap(),
+    }
+}
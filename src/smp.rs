@@ -0,0 +1,301 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+use x86_64::registers::control_regs;
+
+use acpi;
+use backtrace;
+use cpu;
+use interrupts::{self, apic, gdt};
+use memory::{self, MemoryController, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use percpu;
+use sync::{IrqLock, Mutex};
+use syscall;
+use time;
+
+pub const MAX_CPUS: usize = 32;
+// Must match TRAMPOLINE_BASE in trampoline.asm. APs start at the page number given in the
+// startup IPI, so it has to be page aligned and below 1MiB.
+pub const TRAMPOLINE_ADDRESS: PhysicalAddress = 0x8000;
+const AP_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+// Interrupt command register bits
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+// Destination shorthand, the APIC id is ignored
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// Local APIC vector of the TLB shootdown IPI
+const TLB_SHOOTDOWN_VECTOR: u8 = interrupts::LOCAL_VECTOR_BASE + 1;
+
+// How long to wait for an AP after the startup IPIs, in microseconds
+const AP_START_TIMEOUT: u64 = 1_000_000;
+
+const CR4_LA57: u64 = 1 << 12;
+
+static CPU_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+// Bit n is set once CPU n handles interrupts, these are the targets of a TLB shootdown
+static ONLINE_CPUS: AtomicUsize = ATOMIC_USIZE_INIT;
+// CPUs that still have to flush their TLB for the shootdown in progress
+static SHOOTDOWN_PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+// One shootdown at a time
+static SHOOTDOWN: IrqLock<()> = IrqLock::new(());
+// APs are started one at a time. This is set once the current one no longer needs the
+// trampoline and `AP_BOOT`.
+static AP_STARTED: AtomicBool = ATOMIC_BOOL_INIT;
+static AP_BOOT: Mutex<ApBoot> = Mutex::new(ApBoot{
+    stack: 0,
+    ist_stacks: [0; 3],
+    percpu: 0,
+    syscall_stack: 0,
+});
+
+// What the BSP allocated for the AP it's starting
+#[derive(Clone, Copy)]
+struct ApBoot {
+    // Bottom of the stack the AP starts on
+    stack: VirtualAddress,
+    ist_stacks: [VirtualAddress; 3],
+    percpu: VirtualAddress,
+    // Top of the stack
+    syscall_stack: VirtualAddress,
+}
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_params: u8;
+}
+
+// Must match `trampoline_params` in trampoline.asm
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    la57: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+    // Only the AP with this APIC id may use the parameters, an AP that starts after its
+    // timeout parks in the trampoline
+    apic_id: u64,
+    // Set by whoever gets to the parameters first, the AP or the BSP giving up on it
+    claimed: u64,
+}
+
+// Starts every enabled processor in the MADT. The BSP is CPU 0, the APs are numbered in MADT
+// order and end up in `ap_main`. The memory controller is only locked for allocations, not
+// while waiting for the APs.
+pub fn init() {
+    CPU_COUNT.store(1, Ordering::SeqCst);
+    ONLINE_CPUS.store(1, Ordering::SeqCst);
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return,
+    };
+    if !apic::enabled() {
+        return;
+    }
+
+    let (start, end) = unsafe {
+        (&trampoline_start as *const u8 as usize, &trampoline_end as *const u8 as usize)
+    };
+    assert!(end - start <= PAGE_SIZE, "AP trampoline doesn't fit in a page");
+    unsafe {
+        ptr::copy_nonoverlapping(start as *const u8,
+                                 memory::kernel_phys_to_virt(TRAMPOLINE_ADDRESS) as *mut u8,
+                                 end - start);
+    }
+    interrupts::register_local_interrupt(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_interrupt);
+
+    // The trampoline enables paging while running at its physical address
+    let mapped = memory::with_controller(|memory_controller| {
+        memory_controller.identity_map(TRAMPOLINE_ADDRESS)
+    });
+    if !mapped {
+        println!("no memory to map the AP trampoline");
+        return;
+    }
+
+    let bsp = apic::local().id();
+    let mut count = 1;
+    for processor in madt.processors().iter().filter(|p| p.apic_id != bsp) {
+        if count == MAX_CPUS {
+            println!("only {} CPUs are supported", MAX_CPUS);
+            break;
+        }
+        if start_ap(count, processor.apic_id) {
+            count += 1;
+        } else {
+            println!("CPU with APIC id {} didn't start", processor.apic_id);
+        }
+    }
+
+    memory::with_controller(|memory_controller| {
+        memory_controller.unmap_identity(TRAMPOLINE_ADDRESS)
+    });
+    CPU_COUNT.store(count, Ordering::SeqCst);
+    println!("{} CPUs online", count);
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/**
+Makes every other online CPU drop all of its cached translations and waits until they did.
+Needed whenever a mapping changes or goes away, since all CPUs run on the same page tables.
+The caller flushes its own TLB. Other CPUs must not be spinning with interrupts disabled on a
+lock the caller holds.
+**/
+pub fn tlb_shootdown() {
+    let this_cpu = 1 << if percpu::loaded() { percpu::cpu_id() } else { 0 };
+    let targets = ONLINE_CPUS.load(Ordering::SeqCst) & !this_cpu;
+    if targets == 0 {
+        return;
+    }
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        // Another CPU is shooting down and may be waiting for us, with interrupts disabled
+        // we have to answer it here
+        flush_if_pending(this_cpu);
+    };
+    SHOOTDOWN_PENDING.store(targets, Ordering::SeqCst);
+    apic::local().send_ipi(0, ICR_ALL_EXCLUDING_SELF | TLB_SHOOTDOWN_VECTOR as u32);
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {}
+}
+
+fn tlb_shootdown_interrupt() {
+    flush_if_pending(1 << percpu::cpu_id());
+}
+
+// Answers a shootdown directed at this CPU. For code that spins with interrupts disabled on
+// something another CPU holds, which may be shooting down.
+pub fn flush_pending_tlb() {
+    flush_if_pending(1 << if percpu::loaded() { percpu::cpu_id() } else { 0 });
+}
+
+fn flush_if_pending(this_cpu: usize) {
+    if SHOOTDOWN_PENDING.load(Ordering::SeqCst) & this_cpu != 0 {
+        memory::flush_tlb();
+        SHOOTDOWN_PENDING.fetch_and(!this_cpu, Ordering::SeqCst);
+    }
+}
+
+// Sends INIT-SIPI-SIPI to the AP with `apic_id` and waits until it runs Rust code
+fn start_ap(cpu: usize, apic_id: u32) -> bool {
+    let boot = match memory::with_controller(allocate_ap) {
+        Some(allocated) => allocated,
+        None => return false,
+    };
+    *AP_BOOT.lock() = boot;
+
+    let cr3 = control_regs::cr3().0 & 0x000f_ffff_ffff_f000;
+    assert!(cr3 < 1 << 32, "AP trampoline can't load a page table above 4GiB");
+    let params = TrampolineParams{
+        cr3: cr3,
+        la57: if cpu::read_cr4() & CR4_LA57 != 0 { 1 } else { 0 },
+        stack: (boot.stack + AP_STACK_SIZE) as u64,
+        entry: ap_entry as u64,
+        cpu: cpu as u64,
+        apic_id: apic_id as u64,
+        claimed: 0,
+    };
+    let offset = unsafe {
+        &trampoline_params as *const u8 as usize - &trampoline_start as *const u8 as usize
+    };
+    let params_address = memory::kernel_phys_to_virt(TRAMPOLINE_ADDRESS + offset);
+    unsafe { ptr::write_volatile(params_address as *mut TrampolineParams, params) };
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    let local = apic::local();
+    local.send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    time::udelay(10_000);
+    // The second startup IPI is only needed if the first one got lost
+    for _ in 0..2 {
+        local.send_ipi(apic_id, ICR_STARTUP | (TRAMPOLINE_ADDRESS / PAGE_SIZE) as u32);
+        time::udelay(200);
+        if AP_STARTED.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+
+    let mut waited = 0;
+    while !AP_STARTED.load(Ordering::SeqCst) {
+        if waited >= AP_START_TIMEOUT && !claim_params(params_address) {
+            // The AP can't use the parameters anymore, if it shows up late it parks in the
+            // trampoline. Nothing of what we allocated for it will be used.
+            free_ap(&boot);
+            return false;
+        }
+        time::udelay(100);
+        waited += 100;
+    }
+    true
+}
+
+// Claims the trampoline parameters for the BSP. Returns true if the AP got them first, it is
+// starting then and will set `AP_STARTED`.
+fn claim_params(params_address: VirtualAddress) -> bool {
+    let params = params_address as *const TrampolineParams;
+    let claimed = unsafe { &*(&(*params).claimed as *const u64 as *const AtomicUsize) };
+    claimed.swap(1, Ordering::SeqCst) != 0
+}
+
+// Allocates the stack an AP starts on and what it needs to set itself up
+fn allocate_ap(memory_controller: &mut MemoryController) -> Option<ApBoot> {
+    // vmalloc leaves unmapped guard pages between its areas, so an overflow faults
+    let stack = match memory_controller.vmalloc(AP_STACK_SIZE) {
+        Some(stack) => stack,
+        None => return None,
+    };
+    let mut ist_stacks = [0; 3];
+    for top in ist_stacks.iter_mut() {
+        match memory_controller.vmalloc(gdt::IST_STACK_SIZE) {
+            Some(ist_stack) => *top = ist_stack + gdt::IST_STACK_SIZE,
+            None => return None,
+        }
+    }
+    let percpu = match percpu::allocate(memory_controller) {
+        Some(percpu) => percpu,
+        None => return None,
+    };
+    let syscall_stack = match memory_controller.vmalloc(syscall::KERNEL_STACK_SIZE) {
+        Some(syscall_stack) => syscall_stack,
+        None => return None,
+    };
+    Some(ApBoot{
+        stack: stack,
+        ist_stacks: ist_stacks,
+        percpu: percpu,
+        syscall_stack: syscall_stack + syscall::KERNEL_STACK_SIZE,
+    })
+}
+
+// Frees what `allocate_ap` returned for an AP that didn't start
+fn free_ap(boot: &ApBoot) {
+    memory::with_controller(|memory_controller| {
+        memory_controller.vfree(boot.stack);
+        for &top in boot.ist_stacks.iter() {
+            memory_controller.vfree(top - gdt::IST_STACK_SIZE);
+        }
+        memory_controller.vfree(boot.percpu);
+        memory_controller.vfree(boot.syscall_stack - syscall::KERNEL_STACK_SIZE);
+    });
+}
+
+// Jumped to by the trampoline in long mode, on the stack allocated in `start_ap`
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    let boot = *AP_BOOT.lock();
+    unsafe { percpu::load(boot.percpu, cpu) };
+    backtrace::set_start_stack(boot.stack, boot.stack + AP_STACK_SIZE);
+    interrupts::init_ap(cpu, boot.ist_stacks);
+    memory::init_ap();
+    cpu::init_ap();
+    syscall::init_ap(boot.syscall_stack);
+    ONLINE_CPUS.fetch_or(1 << cpu, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+    ::ap_main(cpu)
+}
@@ -0,0 +1,348 @@
+/**
+Lock dependency validator, built with the `lockdep` feature. It catches locking bugs the first
+time the code paths involved run, instead of only when they race:
+
+* Lock order inversions: every time a lock is taken while others are held, "held before taken"
+  edges are recorded between them. A new edge that closes a cycle could deadlock.
+* Recursive locking of a lock the CPU already holds.
+* Interrupt unsafe locks: a lock taken in an interrupt handler and elsewhere with interrupts
+  enabled deadlocks if the interrupt hits while it's held, see `IrqLock`.
+
+Every lock is a class of its own. Acquisition sites are reported as instruction addresses, which
+can be looked up with `addr2line -e kernel.bin`. Like Linux' lockdep, validation turns itself
+off after the first report.
+**/
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+use spin;
+
+use interrupts;
+use percpu;
+
+const MAX_CLASSES: usize = 64;
+// Locks one CPU can hold at the same time
+const MAX_HELD: usize = 16;
+
+// Values of `Mutex::class` other than the class index plus one
+const UNREGISTERED: usize = 0;
+const UNTRACKED: usize = !0;
+
+static DISABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+struct Graph {
+    classes: usize,
+    addresses: [usize; MAX_CLASSES],
+    // Bit b of after[a] is set once b was taken while a was held
+    after: [u64; MAX_CLASSES],
+    // Acquisition sites of a and b when that happened first
+    sites: [[(usize, usize); MAX_CLASSES]; MAX_CLASSES],
+    // First acquisition in an interrupt handler and with interrupts enabled, 0 if none yet
+    irq_site: [usize; MAX_CLASSES],
+    enabled_site: [usize; MAX_CLASSES],
+}
+
+// Not a tracked lock itself, and only taken with interrupts disabled
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph{
+    classes: 0,
+    addresses: [0; MAX_CLASSES],
+    after: [0; MAX_CLASSES],
+    sites: [[(0, 0); MAX_CLASSES]; MAX_CLASSES],
+    irq_site: [0; MAX_CLASSES],
+    enabled_site: [0; MAX_CLASSES],
+});
+
+#[derive(Clone, Copy)]
+struct HeldLocks {
+    count: usize,
+    // Class index and acquisition site
+    locks: [(usize, usize); MAX_HELD],
+}
+
+per_cpu! {
+    static HELD: HeldLocks = HeldLocks{
+        count: 0,
+        locks: [(0, 0); MAX_HELD],
+    };
+}
+
+enum Report {
+    Recursive {
+        lock: usize,
+        site: usize,
+        held_site: usize,
+    },
+    Inversion {
+        lock: usize,
+        site: usize,
+        held: usize,
+        held_site: usize,
+        // The earlier acquisition of `next` while `lock` was held, which starts the cycle
+        next: usize,
+        earlier_site: usize,
+        next_site: usize,
+    },
+    IrqUnsafe {
+        lock: usize,
+        irq_site: usize,
+        enabled_site: usize,
+    },
+}
+
+pub struct Mutex<T: ?Sized> {
+    class: AtomicUsize,
+    inner: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    class: usize,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex{
+            class: ATOMIC_USIZE_INIT,
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    // Inlined so that the site is in the caller
+    #[inline(always)]
+    pub fn lock(&self) -> MutexGuard<T> {
+        let class = self.class();
+        // Validate before spinning, a deadlock would hide the report
+        acquire(class, site(), false);
+        MutexGuard{
+            class: class,
+            guard: self.inner.lock(),
+        }
+    }
+
+    // Can't deadlock, so it adds no dependencies. Locks taken while holding it do.
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let class = self.class();
+        self.inner.try_lock().map(|guard| {
+            acquire(class, site(), true);
+            MutexGuard{
+                class: class,
+                guard: guard,
+            }
+        })
+    }
+
+    fn class(&self) -> usize {
+        let class = self.class.load(Ordering::Acquire);
+        if class != UNREGISTERED {
+            return class;
+        }
+        let class = register(self as *const Self as *const u8 as usize);
+        match self.class.compare_exchange(UNREGISTERED, class, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => class,
+            Err(registered) => registered,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        release(self.class);
+    }
+}
+
+impl Graph {
+    fn check(&mut self, class: usize, site: usize, trylock: bool, held: &HeldLocks) -> Option<Report> {
+        let mut report = None;
+
+        // A trylock in an interrupt handler fails instead of spinning on a lock the interrupted
+        // code holds, so only blocking acquisitions make a lock interrupt-used
+        if interrupts::in_interrupt() {
+            if !trylock && self.irq_site[class] == 0 {
+                self.irq_site[class] = site;
+            }
+        } else if interrupts::enabled() && self.enabled_site[class] == 0 {
+            self.enabled_site[class] = site;
+        }
+        if self.irq_site[class] != 0 && self.enabled_site[class] != 0 {
+            report = Some(Report::IrqUnsafe{
+                lock: self.addresses[class],
+                irq_site: self.irq_site[class],
+                enabled_site: self.enabled_site[class],
+            });
+        }
+        if trylock {
+            return report;
+        }
+
+        for &(held_class, held_site) in held.locks[..held.count].iter() {
+            if held_class == class {
+                return Some(Report::Recursive{
+                    lock: self.addresses[class],
+                    site: site,
+                    held_site: held_site,
+                });
+            }
+            if self.after[held_class] & 1 << class != 0 {
+                continue;
+            }
+            if report.is_none() {
+                if let Some(next) = self.first_step(class, held_class) {
+                    let (earlier_site, next_site) = self.sites[class][next];
+                    report = Some(Report::Inversion{
+                        lock: self.addresses[class],
+                        site: site,
+                        held: self.addresses[held_class],
+                        held_site: held_site,
+                        next: self.addresses[next],
+                        earlier_site: earlier_site,
+                        next_site: next_site,
+                    });
+                }
+            }
+            self.after[held_class] |= 1 << class;
+            self.sites[held_class][class] = (held_site, site);
+        }
+        report
+    }
+
+    // The class after `from` on a dependency path to `to`, if there is one
+    fn first_step(&self, from: usize, to: usize) -> Option<usize> {
+        let mut first = [0; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 0);
+        let mut visited = 0u64;
+        for next in 0..self.classes {
+            if self.after[from] & 1 << next != 0 {
+                first[next] = next;
+                visited |= 1 << next;
+                queue[tail] = next;
+                tail += 1;
+            }
+        }
+        while head < tail {
+            let class = queue[head];
+            head += 1;
+            if class == to {
+                return Some(first[class]);
+            }
+            for next in 0..self.classes {
+                if self.after[class] & 1 << next != 0 && visited & 1 << next == 0 {
+                    first[next] = first[class];
+                    visited |= 1 << next;
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Report {
+    fn print(&self) {
+        println!("\nlockdep: validation turned off after this report");
+        match *self {
+            Report::Recursive{lock, site, held_site} => {
+                println!("recursive locking of {:#x} at {:#x}", lock, site);
+                println!("    already taken at {:#x}", held_site);
+            }
+            Report::Inversion{lock, site, held, held_site, next, earlier_site, next_site} => {
+                println!("possible deadlock, lock order inversion:");
+                println!("    {:#x} taken at {:#x}", lock, site);
+                println!("    while holding {:#x} taken at {:#x}", held, held_site);
+                println!("but earlier {:#x} was taken at {:#x}", next, next_site);
+                println!("    while holding {:#x} taken at {:#x}", lock, earlier_site);
+            }
+            Report::IrqUnsafe{lock, irq_site, enabled_site} => {
+                println!("interrupt unsafe lock {:#x}:", lock);
+                println!("    taken in an interrupt handler at {:#x}", irq_site);
+                println!("    taken with interrupts enabled at {:#x}", enabled_site);
+            }
+        }
+    }
+}
+
+// Address of the instruction after this, i.e. in the caller when inlined
+#[inline(always)]
+fn site() -> usize {
+    let site: usize;
+    unsafe { asm!("leaq (%rip), $0" : "=r"(site) ::: "volatile") };
+    site
+}
+
+fn register(address: usize) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut graph = GRAPH.lock();
+        if graph.classes == MAX_CLASSES {
+            return UNTRACKED;
+        }
+        let class = graph.classes;
+        graph.addresses[class] = address;
+        graph.classes += 1;
+        class + 1
+    })
+}
+
+// Per-CPU data isn't available before `percpu::load`, locks taken until then aren't tracked
+fn tracking() -> bool {
+    !DISABLED.load(Ordering::Relaxed) && percpu::loaded()
+}
+
+fn acquire(class: usize, site: usize, trylock: bool) {
+    if class == UNTRACKED || !tracking() {
+        return;
+    }
+    let class = class - 1;
+    let report = interrupts::without_interrupts(|| {
+        let held = HELD.get();
+        GRAPH.lock().check(class, site, trylock, &held)
+    });
+    if let Some(report) = report {
+        // Printing takes locks as well, so nothing is tracked from here on
+        if !DISABLED.swap(true, Ordering::SeqCst) {
+            report.print();
+        }
+        return;
+    }
+    HELD.with(|held| {
+        if held.count < MAX_HELD {
+            held.locks[held.count] = (class, site);
+            held.count += 1;
+        }
+    });
+}
+
+fn release(class: usize) {
+    if class == UNTRACKED || !tracking() {
+        return;
+    }
+    let class = class - 1;
+    // Locks aren't necessarily released in reverse order
+    HELD.with(|held| {
+        let count = held.count;
+        if let Some(index) = held.locks[..count].iter().rposition(|&(held, _)| held == class) {
+            for i in index..count - 1 {
+                held.locks[i] = held.locks[i + 1];
+            }
+            held.count -= 1;
+        }
+    });
+}
@@ -0,0 +1,84 @@
+use core::ops::{Deref, DerefMut};
+
+use interrupts;
+
+#[cfg(feature = "lockdep")]
+mod lockdep;
+
+// With the `lockdep` feature every lock is checked for ordering and interrupt safety bugs
+#[cfg(feature = "lockdep")]
+pub use self::lockdep::{Mutex, MutexGuard};
+#[cfg(not(feature = "lockdep"))]
+pub use spin::{Mutex, MutexGuard};
+
+/**
+A spinlock that disables interrupts while it's held, so that an interrupt handler taking the
+same lock can't spin forever on the CPU it interrupted. The interrupt flag is restored when the
+guard is dropped, which makes nesting these locks fine.
+
+Everything an interrupt handler may lock has to use this instead of `spin::Mutex`.
+**/
+pub struct IrqLock<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqLockGuard<'a, T: 'a> {
+    // Only None while dropping, the lock is released before interrupts are enabled again
+    guard: Option<MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqLock<T> {
+    pub const fn new(value: T) -> IrqLock<T> {
+        IrqLock{
+            inner: Mutex::new(value),
+        }
+    }
+
+    // Inlined so that lockdep records the caller as the acquisition site
+    #[inline(always)]
+    pub fn lock(&self) -> IrqLockGuard<T> {
+        let interrupts_enabled = interrupts::save_and_disable();
+        IrqLockGuard{
+            guard: Some(self.inner.lock()),
+            interrupts_enabled: interrupts_enabled,
+        }
+    }
+
+    // Returns None instead of spinning if the lock is held, e.g. by the code a panic interrupted
+    #[inline(always)]
+    pub fn try_lock(&self) -> Option<IrqLockGuard<T>> {
+        let interrupts_enabled = interrupts::save_and_disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqLockGuard{
+                guard: Some(guard),
+                interrupts_enabled: interrupts_enabled,
+            }),
+            None => {
+                interrupts::restore(interrupts_enabled);
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T: 'a> Deref for IrqLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: 'a> DerefMut for IrqLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: 'a> Drop for IrqLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        interrupts::restore(self.interrupts_enabled);
+    }
+}
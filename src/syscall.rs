@@ -0,0 +1,186 @@
+/**
+The `syscall`/`sysret` system call interface. The ABI follows the Linux one:
+
+    register  use
+    rax       system call number, result on return
+    rdi       first argument
+    rsi       second argument
+    rdx       third argument
+    r10       fourth argument (rcx is taken by `syscall` for the return address)
+    r8        fifth argument
+    r9        sixth argument
+
+rcx and r11 are clobbered, all other registers are preserved. Errors are returned as negative
+numbers, e.g. `ENOSYS` for unknown system calls.
+**/
+
+use core::{slice, str};
+
+use x86_64::registers::msr::{rdmsr, wrmsr};
+
+use interrupts::{self, gdt};
+use memory::{self, MemoryController, VirtualAddress};
+use percpu;
+use time;
+use user;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+
+const EFER_SYSCALL_ENABLE: u64 = 1 << 0;
+
+/**
+STAR holds the selector bases loaded by `syscall` and `sysret`:
+
+    bits    loaded by   selectors
+    32-47   syscall     CS = base, SS = base + 8
+    48-63   sysret      CS = base + 16, SS = base + 8 (both with RPL 3)
+
+The GDT has kernel code, kernel data, user data, user code in that order to make this work.
+**/
+const STAR_SYSCALL_SHIFT: u64 = 32;
+const STAR_SYSRET_SHIFT: u64 = 48;
+const SYSRET_BASE: u16 = gdt::USER_DATA_SELECTOR - 8;
+
+// RFLAGS bits cleared on entry: trap, interrupt, direction, nested task and alignment check
+const SYSCALL_FLAG_MASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 14 | 1 << 18;
+
+pub const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+// End of the lower half with 4-level paging, user memory lies below
+const USER_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+pub const ENOSYS: i64 = -38;
+pub const EFAULT: i64 = -14;
+pub const EINVAL: i64 = -22;
+
+// Exception a program is reported killed by when it can't be returned to
+const GENERAL_PROTECTION_FAULT: u8 = 13;
+
+pub const SYS_PRINT: usize = 0;
+pub const SYS_UPTIME: usize = 1;
+pub const SYS_CPU_ID: usize = 2;
+pub const SYS_EXIT: usize = 3;
+
+type Handler = fn(u64, u64, u64, u64, u64, u64) -> i64;
+
+// Indexed by system call number
+static SYSCALLS: [Handler; 4] = [
+    sys_print,
+    sys_uptime,
+    sys_cpu_id,
+    sys_exit,
+];
+
+// The registers `syscall.asm` saves on the kernel stack, in stack order
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// Enables system calls on the bootstrap processor
+pub fn init(memory_controller: &mut MemoryController) {
+    let stack = memory_controller.vmalloc(KERNEL_STACK_SIZE)
+        .expect("no memory for the system call stack");
+    init_ap(stack + KERNEL_STACK_SIZE);
+}
+
+/**
+Enables system calls on the calling CPU, running them on the stack ending at `kernel_stack`.
+Interrupts from user mode use the same stack: they can't arrive while a system call runs on it
+since the CPU is in kernel mode then.
+**/
+pub fn init_ap(kernel_stack: VirtualAddress) {
+    percpu::set_kernel_stack(kernel_stack);
+    gdt::set_privilege_stack(percpu::cpu_id(), kernel_stack);
+    let star = (SYSRET_BASE as u64) << STAR_SYSRET_SHIFT
+        | (gdt::KERNEL_CODE_SELECTOR as u64) << STAR_SYSCALL_SHIFT;
+    unsafe {
+        wrmsr(IA32_STAR, star);
+        wrmsr(IA32_LSTAR, syscall_entry as u64);
+        wrmsr(IA32_FMASK, SYSCALL_FLAG_MASK);
+        wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SYSCALL_ENABLE);
+    }
+}
+
+// Called by `syscall_entry` with interrupts disabled, which it expects again on return
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // `sysret` to a non-canonical address raises #GP in ring 0 with the user GS base loaded.
+    // User code lies below `USER_END`, a return address past it can only come from a program
+    // that ran off the end of the user half.
+    if frame.rip >= USER_END as u64 {
+        user::exit(user::Exit::Killed(GENERAL_PROTECTION_FAULT));
+    }
+    interrupts::enable();
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(handler) => handler(frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9),
+        None => ENOSYS,
+    };
+    frame.rax = result as u64;
+    interrupts::disable();
+}
+
+// Checks that `length` bytes at `address` are user memory the program has mapped. Only the
+// calling program can unmap them, so they stay valid until the system call returns. Pages that
+// get swapped out in the meantime are read back in by the page fault handler.
+fn user_slice(address: u64, length: u64) -> Option<&'static [u8]> {
+    match address.checked_add(length) {
+        Some(end) if end <= USER_END as u64 => {}
+        _ => return None,
+    }
+    let (address, length) = (address as VirtualAddress, length as usize);
+    let mapped = memory::with_controller(|memory_controller| {
+        memory_controller.is_user_accessible(address, length)
+    });
+    if !mapped {
+        return None;
+    }
+    Some(unsafe { slice::from_raw_parts(address as *const u8, length) })
+}
+
+// print(string, length): writes a UTF-8 string to the console
+fn sys_print(address: u64, length: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    let bytes = match user_slice(address, length) {
+        Some(bytes) => bytes,
+        None => return EFAULT,
+    };
+    match str::from_utf8(bytes) {
+        Ok(string) => {
+            print!("{}", string);
+            length as i64
+        }
+        Err(_) => EINVAL,
+    }
+}
+
+// uptime(): nanoseconds since boot
+fn sys_uptime(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    time::uptime() as i64
+}
+
+// cpu_id(): number of the CPU the caller runs on
+fn sys_cpu_id(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    percpu::cpu_id() as i64
+}
+
+// exit(code): ends the user program, `user::run` returns `code`. Doesn't go back through
+// `syscall_entry`: its `swapgs` pairs with the one in `enter_user` instead.
+fn sys_exit(code: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    user::exit(user::Exit::Exited(code as i64))
+}
@@ -0,0 +1,83 @@
+use core::ptr;
+
+use spin::Once;
+
+use acpi;
+use memory::{MemoryController, VirtualAddress};
+use super::ClockSource;
+
+// Register offsets
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const REGISTERS_SIZE: usize = 0x400;
+
+// Capabilities: the main counter is 64 bits wide
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+// Configuration: the main counter runs
+const ENABLE_CNF: u64 = 1 << 0;
+// Configuration: timers 0 and 1 replace the PIT and RTC interrupts
+const LEG_RT_CNF: u64 = 1 << 1;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+static HPET: Once<Hpet> = Once::new();
+
+pub struct Hpet {
+    base: VirtualAddress,
+    frequency: u64,
+}
+
+impl Hpet {
+    fn read(&self, register: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + register) as *const u64) }
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + register) as *mut u64, value) }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+// Maps and starts the HPET that ACPI reports. Its timers aren't used, only the main counter.
+// HPETs with a 32-bit counter wrap every few minutes and are ignored.
+pub fn init(memory_controller: &mut MemoryController) -> Option<&'static Hpet> {
+    let address = match acpi::hpet() {
+        Some(address) => address,
+        None => return None,
+    };
+    let base = match memory_controller.ioremap(address, REGISTERS_SIZE) {
+        Some(base) => base,
+        None => return None,
+    };
+
+    let mut hpet = Hpet{
+        base: base,
+        frequency: 0,
+    };
+    let capabilities = hpet.read(CAPABILITIES);
+    // The upper half holds the counter period in femtoseconds
+    let period = capabilities >> 32;
+    if capabilities & COUNT_SIZE_CAP == 0 || period == 0 {
+        memory_controller.iounmap(base);
+        return None;
+    }
+    hpet.frequency = FEMTOSECONDS_PER_SECOND / period;
+
+    let configuration = hpet.read(CONFIGURATION) & !LEG_RT_CNF;
+    hpet.write(CONFIGURATION, configuration | ENABLE_CNF);
+    Some(HPET.call_once(|| hpet))
+}
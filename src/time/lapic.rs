@@ -0,0 +1,59 @@
+use spin::Once;
+
+use interrupts::{self, apic};
+use super::{ClockEvent, NSEC_PER_SEC};
+
+// Divide configuration values for `TIMER_DIVIDE`, the bits are scattered over 0, 1 and 3
+const DIVIDE_BY_16: u32 = 0b0011;
+const PERIODIC: u32 = 1 << 17;
+// How long the timer runs against the reference clock during calibration
+const CALIBRATION_NS: u64 = 10_000_000;
+
+static LAPIC_TIMER: Once<LapicTimer> = Once::new();
+
+// Each CPU has its own local APIC timer, which runs at the (unknown) bus frequency
+pub struct LapicTimer {
+    // Timer decrements per second with `DIVIDE_BY_16`
+    frequency: u64,
+}
+
+impl ClockEvent for LapicTimer {
+    fn name(&self) -> &'static str {
+        "lapic"
+    }
+
+    // Only starts the timer of the calling CPU
+    fn start_periodic(&self, hz: u32, handler: fn()) {
+        let local = apic::local();
+        interrupts::register_local_interrupt(apic::TIMER_VECTOR, handler);
+        local.write(apic::TIMER_DIVIDE, DIVIDE_BY_16);
+        local.write(apic::LVT_TIMER, PERIODIC | apic::TIMER_VECTOR as u32);
+        local.write(apic::TIMER_INITIAL_COUNT, (self.frequency / hz as u64) as u32);
+    }
+
+    fn stop(&self) {
+        let local = apic::local();
+        local.write(apic::LVT_TIMER, apic::LVT_MASKED);
+        local.write(apic::TIMER_INITIAL_COUNT, 0);
+    }
+}
+
+// Measures the timer frequency with `wait`, which busy waits on a reference clock
+pub fn init<F>(wait: F) -> &'static LapicTimer
+where
+    F: Fn(u64),
+{
+    LAPIC_TIMER.call_once(|| {
+        let local = apic::local();
+        local.write(apic::TIMER_DIVIDE, DIVIDE_BY_16);
+        local.write(apic::LVT_TIMER, apic::LVT_MASKED);
+        local.write(apic::TIMER_INITIAL_COUNT, u32::max_value());
+        wait(CALIBRATION_NS);
+        let elapsed = u32::max_value() - local.read(apic::TIMER_CURRENT_COUNT);
+        local.write(apic::TIMER_INITIAL_COUNT, 0);
+
+        LapicTimer{
+            frequency: elapsed as u64 * NSEC_PER_SEC / CALIBRATION_NS,
+        }
+    })
+}
@@ -0,0 +1,249 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Once;
+
+use interrupts::apic;
+use memory::MemoryController;
+use sync::IrqLock;
+
+mod hpet;
+mod lapic;
+mod pit;
+mod rtc;
+mod tsc;
+
+pub use self::rtc::{DateTime, RTC};
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+// Frequency of the periodic tick
+pub const TICK_HZ: u32 = 100;
+const MAX_TIMERS: usize = 32;
+// Clock sources at least this fast are used for `ndelay`
+const PRECISE_FREQUENCY: u64 = 1_000_000;
+// Longest wait `pit::wait` supports, in nanoseconds
+const PIT_MAX_WAIT: u64 = 50_000_000;
+
+// A free running counter time is measured with
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    fn read(&self) -> u64;
+    // Counter increments per second
+    fn frequency(&self) -> u64;
+}
+
+// A device that raises interrupts at programmable times
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+    // Calls `handler` from the device's interrupt `hz` times per second
+    fn start_periodic(&self, hz: u32, handler: fn());
+    fn stop(&self);
+}
+
+// The clock source in use and its value at boot
+struct Clock {
+    source: &'static ClockSource,
+    start: u64,
+}
+
+static CLOCK: Once<Clock> = Once::new();
+static CLOCK_EVENT: Once<&'static ClockEvent> = Once::new();
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+// Unix time in nanoseconds read from the RTC and the value of `now()` at that moment
+static BOOT_WALL_TIME: Once<(u64, u64)> = Once::new();
+
+// Counts ticks, the fallback clock source without an HPET. Its resolution is one tick.
+struct Jiffies;
+
+static JIFFIES: Jiffies = Jiffies;
+
+impl ClockSource for Jiffies {
+    fn name(&self) -> &'static str {
+        "jiffies"
+    }
+
+    fn read(&self) -> u64 {
+        TICKS.load(Ordering::Relaxed) as u64
+    }
+
+    fn frequency(&self) -> u64 {
+        TICK_HZ as u64
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    // Uptime in nanoseconds at which the callback runs
+    deadline: u64,
+    callback: fn(),
+    // Tells this timer apart from earlier ones in the same slot
+    generation: usize,
+}
+
+// Returned by `add_timer`. Stays invalid once the timer ran, even if its slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    slot: usize,
+    generation: usize,
+}
+
+static TIMERS: IrqLock<[Option<Timer>; MAX_TIMERS]> = IrqLock::new([None; MAX_TIMERS]);
+static TIMER_GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Picks the best clock source, the invariant TSC, the HPET or the tick count in that order, and
+// starts the periodic tick. The LAPIC timer is preferred over the PIT when the APIC is in use.
+// The TSC and the LAPIC timer are calibrated against the HPET or the PIT.
+pub fn init(memory_controller: &mut MemoryController) {
+    let hpet = hpet::init(memory_controller);
+    let reference_wait = |ns| match hpet {
+        Some(hpet) => busy_wait(hpet, ns),
+        None => pit::wait(ns),
+    };
+
+    let tsc = tsc::init(&reference_wait);
+    let source: &'static ClockSource = match (tsc, hpet) {
+        (Some(tsc), _) => tsc,
+        (None, Some(hpet)) => hpet,
+        (None, None) => &JIFFIES,
+    };
+    CLOCK.call_once(|| Clock{
+        source: source,
+        start: source.read(),
+    });
+
+    let event: &'static ClockEvent = if apic::enabled() {
+        lapic::init(&reference_wait)
+    } else {
+        &pit::PIT
+    };
+    CLOCK_EVENT.call_once(|| event);
+    event.start_periodic(TICK_HZ, tick);
+
+    // The RTC only has a resolution of one second, wall time advances with `now()` afterwards
+    BOOT_WALL_TIME.call_once(|| (rtc::read().to_unix() * NSEC_PER_SEC, now()));
+
+    println!("clock source: {}, clock event: {} at {} Hz", source.name(), event.name(), TICK_HZ);
+    println!("wall time: {}", wall_time());
+}
+
+// Nanoseconds since `init`
+pub fn uptime() -> u64 {
+    match CLOCK.try() {
+        Some(clock) => to_nanoseconds(clock.source.read() - clock.start, clock.source.frequency()),
+        None => 0,
+    }
+}
+
+// Monotonic nanoseconds since an arbitrary point during boot, only differences are meaningful.
+// Reads the TSC directly when it's usable, which is cheaper than going through the clock source.
+pub fn now() -> u64 {
+    match tsc::get() {
+        Some(tsc) => to_nanoseconds(tsc::read(), tsc.frequency()),
+        None => uptime(),
+    }
+}
+
+// Nanoseconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    match BOOT_WALL_TIME.try() {
+        Some(&(wall, monotonic)) => wall + (now() - monotonic),
+        None => rtc::read().to_unix() * NSEC_PER_SEC,
+    }
+}
+
+pub fn wall_time() -> DateTime {
+    DateTime::from_unix(unix_time() / NSEC_PER_SEC)
+}
+
+// Busy waits at least `ns` nanoseconds
+pub fn ndelay(ns: u64) {
+    match precise_source() {
+        Some(source) => busy_wait(source, ns),
+        None => {
+            // Tick resolution is too coarse, wait on PIT channel 2 in steps it can handle
+            let mut left = ns;
+            while left > 0 {
+                let step = if left > PIT_MAX_WAIT { PIT_MAX_WAIT } else { left };
+                pit::wait(step);
+                left -= step;
+            }
+        }
+    }
+}
+
+pub fn udelay(us: u64) {
+    ndelay(us * 1000);
+}
+
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Runs `callback` once after `delay` nanoseconds, from the tick interrupt. The delay is
+// rounded up to the next tick. Returns an id for `cancel_timer`.
+pub fn add_timer(delay: u64, callback: fn()) -> Option<TimerId> {
+    let deadline = uptime() + delay;
+    let generation = TIMER_GENERATION.fetch_add(1, Ordering::Relaxed);
+    let mut timers = TIMERS.lock();
+    let slot = timers.iter().position(|timer| timer.is_none());
+    slot.map(|slot| {
+        timers[slot] = Some(Timer{
+            deadline: deadline,
+            callback: callback,
+            generation: generation,
+        });
+        TimerId{
+            slot: slot,
+            generation: generation,
+        }
+    })
+}
+
+// Returns false if the timer already ran or was cancelled
+pub fn cancel_timer(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    let current = timers.get(id.slot)
+        .map_or(false, |timer| timer.map_or(false, |timer| timer.generation == id.generation));
+    if current {
+        timers[id.slot] = None;
+    }
+    current
+}
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    let now = uptime();
+    loop {
+        // Take one expired timer at a time, callbacks may add new timers
+        let expired = {
+            let mut timers = TIMERS.lock();
+            let slot = timers.iter()
+                .position(|timer| timer.map_or(false, |timer| timer.deadline <= now));
+            slot.and_then(|slot| timers[slot].take())
+        };
+        match expired {
+            Some(timer) => (timer.callback)(),
+            None => break,
+        }
+    }
+}
+
+// The clock source if it can measure delays of a few nanoseconds to microseconds
+fn precise_source() -> Option<&'static ClockSource> {
+    match CLOCK.try() {
+        Some(clock) if clock.source.frequency() >= PRECISE_FREQUENCY => Some(clock.source),
+        _ => None,
+    }
+}
+
+// Spins until `source` advanced by `ns` nanoseconds
+fn busy_wait(source: &ClockSource, ns: u64) {
+    let start = source.read();
+    while to_nanoseconds(source.read() - start, source.frequency()) < ns {}
+}
+
+// Split into seconds first so that the multiplication can't overflow
+fn to_nanoseconds(count: u64, frequency: u64) -> u64 {
+    let seconds = count / frequency;
+    let rest = count % frequency;
+    seconds * NSEC_PER_SEC + rest * NSEC_PER_SEC / frequency
+}
@@ -0,0 +1,72 @@
+use x86_64::instructions::port::{inb, outb};
+
+use interrupts;
+use super::{ClockEvent, NSEC_PER_SEC};
+
+// Input clock of all three channels
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+const PORT_B: u16 = 0x61;
+
+/**
+The bit layout of the mode/command register is as follows:
+    Bit(s)  Name            Meaning
+    0       BCD             0: binary counter
+    1-3     operating mode  0: interrupt on terminal count, 2: rate generator
+    4-5     access mode     3: low byte, then high byte
+    6-7     channel
+**/
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b00_11_010_0;
+const CHANNEL_2_ONE_SHOT: u8 = 0b10_11_000_0;
+
+// Channel 0 is wired to ISA IRQ 0
+const IRQ: u8 = 0;
+
+pub struct Pit;
+
+pub static PIT: Pit = Pit;
+
+impl ClockEvent for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn start_periodic(&self, hz: u32, handler: fn()) {
+        let divisor = FREQUENCY / hz as u64;
+        assert!(divisor > 0 && divisor <= 0x10000, "PIT can't tick at {} Hz", hz);
+        unsafe {
+            outb(COMMAND, CHANNEL_0_RATE_GENERATOR);
+            // A reload value of 0 means 0x10000
+            outb(CHANNEL_0, divisor as u8);
+            outb(CHANNEL_0, (divisor >> 8) as u8);
+        }
+        interrupts::register_irq(IRQ, handler);
+    }
+
+    fn stop(&self) {
+        interrupts::unregister_irq(IRQ);
+    }
+}
+
+// Busy waits `ns` nanoseconds with channel 2, which has no interrupt and doesn't disturb the
+// tick. Limited to 0xffff PIT cycles (about 55ms), short waits last at least one cycle.
+pub fn wait(ns: u64) {
+    let count = (ns * FREQUENCY + NSEC_PER_SEC - 1) / NSEC_PER_SEC;
+    assert!(count > 0 && count <= 0xffff, "PIT can't wait {}ns", ns);
+    unsafe {
+        // Gate off and speaker off while programming the counter
+        let port_b = inb(PORT_B) & !0b11;
+        outb(PORT_B, port_b);
+        outb(COMMAND, CHANNEL_2_ONE_SHOT);
+        outb(CHANNEL_2, count as u8);
+        outb(CHANNEL_2, (count >> 8) as u8);
+        // Counting starts when the gate goes high, the output goes high at terminal count
+        outb(PORT_B, port_b | 1);
+        while inb(PORT_B) & (1 << 5) == 0 {}
+        outb(PORT_B, port_b);
+    }
+}
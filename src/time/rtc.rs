@@ -0,0 +1,237 @@
+use core::fmt;
+
+use x86_64::instructions::port::{inb, outb};
+
+use interrupts;
+use super::ClockEvent;
+use sync::IrqLock;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+// Status A: the time registers are being updated and must not be read
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// Status B: the hour register is in 24 hour format
+const HOUR_24: u8 = 1 << 1;
+// Status B: values are binary instead of BCD
+const BINARY: u8 = 1 << 2;
+// Status B: periodic interrupt enable
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+// In 12 hour mode this bit of the hour register is set for PM
+const HOUR_PM: u8 = 1 << 7;
+
+// The RTC is wired to ISA IRQ 8
+const IRQ: u8 = 8;
+// The periodic interrupt divides this base frequency by 2^(rate - 1)
+const BASE_FREQUENCY: u32 = 32768;
+
+// The index port is shared by all CMOS accesses, including the interrupt handler
+static CMOS: IrqLock<()> = IrqLock::new(());
+static PERIODIC_HANDLER: IrqLock<Option<fn()>> = IrqLock::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00 UTC
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        let time = seconds % 86400;
+        DateTime{
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/**
+Reads the current date and time. The registers are read twice after waiting for the update in
+progress flag to clear until both reads agree, so an update in between can't give torn
+values. The RTC is assumed to run in UTC and the century to be 20xx, the CMOS century register
+isn't standardized.
+**/
+pub fn read() -> DateTime {
+    let _cmos = CMOS.lock();
+    let mut last = read_raw();
+    loop {
+        let current = read_raw();
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+
+    let status_b = read_register(STATUS_B);
+    let decode = |value: u8| if status_b & BINARY != 0 { value } else { from_bcd(value) };
+
+    let mut hour = decode(last.hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight and 12 PM noon
+        hour %= 12;
+        if last.hour & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    DateTime{
+        year: 2000 + decode(last.year) as u16,
+        month: decode(last.month),
+        day: decode(last.day),
+        hour: hour,
+        minute: decode(last.minute),
+        second: decode(last.second),
+    }
+}
+
+// The periodic interrupt as an additional tick source. It only runs at powers of two from
+// 2 to 8192 Hz.
+pub struct Rtc;
+
+pub static RTC: Rtc = Rtc;
+
+impl ClockEvent for Rtc {
+    fn name(&self) -> &'static str {
+        "rtc"
+    }
+
+    fn start_periodic(&self, hz: u32, handler: fn()) {
+        assert!(hz.is_power_of_two() && hz >= 2 && hz <= 8192, "RTC can't tick at {} Hz", hz);
+        // 2^(rate - 1) = BASE_FREQUENCY / hz
+        let rate = (BASE_FREQUENCY / hz).trailing_zeros() as u8 + 1;
+
+        *PERIODIC_HANDLER.lock() = Some(handler);
+        {
+            let _cmos = CMOS.lock();
+            let status_a = read_register(STATUS_A);
+            write_register(STATUS_A, (status_a & 0xf0) | rate);
+            let status_b = read_register(STATUS_B);
+            write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+            // Acknowledge anything pending, otherwise no further interrupt is raised
+            read_register(STATUS_C);
+        }
+        interrupts::register_irq(IRQ, periodic_interrupt);
+    }
+
+    fn stop(&self) {
+        interrupts::unregister_irq(IRQ);
+        {
+            let _cmos = CMOS.lock();
+            let status_b = read_register(STATUS_B);
+            write_register(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+        }
+        *PERIODIC_HANDLER.lock() = None;
+    }
+}
+
+fn periodic_interrupt() {
+    {
+        let _cmos = CMOS.lock();
+        read_register(STATUS_C);
+    }
+    let handler = *PERIODIC_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+// The time registers as stored, i.e. possibly BCD and 12 hour format
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_raw() -> RawTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    RawTime{
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        outb(INDEX, register);
+        inb(DATA)
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        outb(INDEX, register);
+        outb(DATA, value);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar (Howard Hinnant's
+// algorithm, with years starting in March so that the leap day comes last)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,59 @@
+use spin::Once;
+
+use cpu::{self, Features};
+use super::{ClockSource, NSEC_PER_SEC};
+
+// How long the TSC runs against the reference clock during calibration
+const CALIBRATION_NS: u64 = 10_000_000;
+
+static TSC: Once<Tsc> = Once::new();
+
+// The time stamp counter, only used if it's invariant, i.e. ticks at a constant rate in all
+// power states and isn't stopped in deep C-states
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+pub fn read() -> u64 {
+    let (low, high): (u32, u32);
+    // lfence keeps rdtsc from being executed before earlier instructions
+    unsafe { asm!("lfence; rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile") };
+    (high as u64) << 32 | low as u64
+}
+
+// Measures the TSC frequency with `wait`, which busy waits on a reference clock. Returns None
+// if the TSC isn't invariant.
+pub fn init<F>(wait: F) -> Option<&'static Tsc>
+where
+    F: Fn(u64),
+{
+    if !cpu::has(Features::INVARIANT_TSC) {
+        return None;
+    }
+    Some(TSC.call_once(|| {
+        let start = read();
+        wait(CALIBRATION_NS);
+        let elapsed = read() - start;
+        Tsc{
+            frequency: elapsed * (NSEC_PER_SEC / CALIBRATION_NS),
+        }
+    }))
+}
+
+pub fn get() -> Option<&'static Tsc> {
+    TSC.try()
+}
@@ -0,0 +1,93 @@
+use core::{ptr, slice};
+
+use cpu::fpu::ExtendedState;
+use interrupts;
+use memory::{self, VirtualAddress, PAGE_SIZE};
+
+// Where `run` maps the program and its stack
+const CODE_ADDRESS: VirtualAddress = 0x40_0000;
+const STACK_TOP: VirtualAddress = 0x80_0000;
+const STACK_PAGES: usize = 4;
+
+// How a user program ended
+#[derive(Debug, Clone, Copy)]
+pub enum Exit {
+    // Through the exit system call, with its code
+    Exited(i64),
+    // By an exception, with its vector
+    Killed(u8),
+}
+
+per_cpu! {
+    // Set by `exit` for `run` on the same CPU
+    static EXIT: Option<Exit> = None;
+}
+
+per_cpu! {
+    // x87/SSE/AVX registers every program `run` on this CPU starts with
+    static USER_STATE: ExtendedState = ExtendedState::new();
+}
+
+extern "C" {
+    fn enter_user(entry: VirtualAddress, stack: VirtualAddress);
+    fn exit_user() -> !;
+    static user_test_start: u8;
+    static user_test_end: u8;
+}
+
+/**
+Runs the position independent machine code `program` in ring 3 until it exits or is killed.
+The program and a stack are mapped in the user half of the current page table, which has to
+be empty, and everything in it is freed afterwards. The memory controller must not be locked:
+the program's pages may be swapped out and faults on them need it.
+**/
+pub fn run(program: &[u8]) -> Option<Exit> {
+    let code_pages = (program.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let stack_bottom = STACK_TOP - STACK_PAGES * PAGE_SIZE;
+    let mapped = memory::with_controller(|memory_controller| {
+        memory_controller.map_user(CODE_ADDRESS, code_pages, false)
+            && memory_controller.map_user(stack_bottom, STACK_PAGES, true)
+    });
+    if !mapped {
+        memory::with_controller(|memory_controller| memory_controller.free_user_memory());
+        return None;
+    }
+    // The kernel can write read-only pages, CR0.WP is clear
+    unsafe { ptr::copy_nonoverlapping(program.as_ptr(), CODE_ADDRESS as *mut u8, program.len()) };
+
+    let interrupts_enabled = interrupts::enabled();
+    EXIT.set(None);
+    // The program starts with the default control words and can't see the registers of the
+    // previous one or the kernel. Its state isn't saved when it ends, nothing runs it again.
+    USER_STATE.with(|state| {
+        state.reset();
+        state.restore();
+    });
+    unsafe { enter_user(CODE_ADDRESS, STACK_TOP) };
+    // Exceptions kill the program with interrupts disabled
+    interrupts::restore(interrupts_enabled);
+
+    memory::with_controller(|memory_controller| memory_controller.free_user_memory());
+    EXIT.get()
+}
+
+// Ends the user program running on this CPU, `run` returns `exit`
+pub fn exit(exit: Exit) -> ! {
+    interrupts::disable();
+    EXIT.set(Some(exit));
+    unsafe { exit_user() }
+}
+
+// Runs the program embedded in user.asm, which makes a system call and is killed by a fault
+pub fn test() {
+    let program = unsafe {
+        let start = &user_test_start as *const u8;
+        let length = &user_test_end as *const u8 as usize - start as usize;
+        slice::from_raw_parts(start, length)
+    };
+    match run(program) {
+        Some(Exit::Killed(vector)) => println!("user program killed by exception {}", vector),
+        Some(Exit::Exited(code)) => println!("user program exited with {}", code),
+        None => println!("no memory for the user program"),
+    }
+}
@@ -2,7 +2,8 @@ use core::ptr::Unique;
 use core::fmt;
 
 use volatile::Volatile;
-use spin::Mutex;
+
+use sync::IrqLock;
 
 /**
  * MACROS
@@ -13,9 +14,11 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+// Output goes to the screen and the serial port
 macro_rules! print {
     ($($arg:tt)*) => ({
         $crate::vga_buffer::print(format_args!($($arg)*));
+        $crate::serial::print(format_args!($($arg)*));
     });
 }
 
@@ -144,11 +147,12 @@ impl fmt::Write for Writer {
  * Exported
  */
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+// Interrupt handlers print too, so this must be an IrqLock
+pub static WRITER: IrqLock<Writer> = IrqLock::new(Writer {
     col: 0,
     row: 0,
     color_code: ColorCode::new(Color::LightGreen, Color::Black),
-    buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new_unchecked((::memory::KERNEL_OFFSET + 0xb8000) as *mut _) },
 });
 
 pub fn clear_screen() {
@@ -162,3 +166,25 @@ pub fn print(args: fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
 }
+
+/**
+Prints from the panic handler. The panic may have interrupted code holding `WRITER`, or have
+been raised while it was held, so spinning on it could hang instead of showing the message. In
+that case the output goes through a second writer starting at the last row.
+**/
+pub fn print_panic(args: fmt::Arguments) {
+    use core::fmt::Write;
+    match WRITER.try_lock() {
+        Some(mut writer) => writer.write_fmt(args).unwrap(),
+        None => {
+            let mut writer = Writer {
+                col: 0,
+                row: BUFFER_HEIGHT-1,
+                color_code: ColorCode::new(Color::LightRed, Color::Black),
+                buffer: unsafe { Unique::new_unchecked((::memory::KERNEL_OFFSET + 0xb8000) as *mut _) },
+            };
+            writer.new_line();
+            writer.write_fmt(args).unwrap();
+        }
+    }
+}